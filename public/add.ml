@@ -1,22 +1,26 @@
-<!DOCTYPE html>
-<html lang="en">
-  <head>
-    <meta charset="utf-8">
-    <title>Addition</title>
-  </head>
-  <body>
-    <%
+<% layout("layout.ml"); block("title", "Addition") -%>
+    <%-
+        let field = fn(name) {
+            if (post[name] == null) {
+                throw {"kind": "Missing", "message": "Please fill in " + name + "."}
+            }
+            post[name]
+        };
         if (post["a"] != null) {
-    %>
-    Result is:
-    <%
-            println(post["a"] + post["b"])
+            try {
+                let sum = field("a") + field("b");
+    -%>
+    Result is: <%= sum %>
+    <%-
+            } catch (e) {
+    -%>
+    <p><%= e["message"] %></p>
+    <%-
+            }
         }
-    %>
+    -%>
     <form action="#" method="POST">
         <input name="a" />
         <input name="b" />
         <input type="submit" />
     </form>
-  </body>
-</html>
@@ -1,10 +1,4 @@
-<!DOCTYPE html>
-<html lang="en">
-  <head>
-    <meta charset="utf-8">
-    <title>Index page</title>
-  </head>
-  <body>
+<% layout("layout.ml"); block("title", "Index page") -%>
     <h1>Welcome!</h1>
     <p>
         <a href="/add.ml">Addition</a>
@@ -15,5 +9,3 @@
     <p>
         <a href="/slow.ml">Slow</a>
     </p>
-  </body>
-</html>
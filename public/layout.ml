@@ -0,0 +1,10 @@
+<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <title><% block("title", "monkeylang") %></title>
+  </head>
+  <body>
+<%- block("content") -%>
+  </body>
+</html>
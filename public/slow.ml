@@ -1,12 +1,6 @@
-<!DOCTYPE html>
-<html lang="en">
-  <head>
-    <meta charset="utf-8">
-    <title>Slow page</title>
-  </head>
-  <body>
+<% layout("layout.ml"); block("title", "Slow page") -%>
     Big decreasing list:
-    <%
+    <%-
         let make_list = fn(elements, cur) {
             return if (elements == 0) {
                 cur
@@ -16,6 +10,4 @@
         };
         let big_list = make_list(100, []);
         println(big_list);
-    %>
-  </body>
-</html>
+    -%>
@@ -1,3 +1,6 @@
+use lexer::Position;
+use std::collections::HashMap;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expression {
     Int(i32),
@@ -16,12 +19,29 @@ pub enum Expression {
     False,
     Null,
     If(Box<Expression>, Box<Statement>, Box<Statement>),
-    FnDecl(Vec<String>, Box<Statement>),
+    FnDecl(Vec<Param>, Box<Statement>),
     Call(Box<Expression>, Vec<Expression>),
     String(String),
     Array(Vec<Expression>),
     Index(Box<Expression>, Box<Expression>),
     Hash(Vec<(Expression, Expression)>),
+    /// `...array` among the arguments of a call, passing the elements as arguments of their own
+    Spread(Box<Expression>),
+}
+
+/// A parameter of a function: `name`, `name = default`, or `...name` taking the rest of the
+/// arguments as an array. Defaults are evaluated when the function is called.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Param {
+    pub name: String,
+    pub default: Option<Expression>,
+    pub rest: bool,
+}
+
+impl Param {
+    pub fn new(name: &str) -> Param {
+        Param{ name: String::from(name), default: None, rest: false }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -30,16 +50,25 @@ pub enum Statement {
     Ret(Expression),
     BlockStatement(Vec<Statement>),
     ExprStatement(Expression),
+    /// `throw value`, raising an error that carries the value
+    Throw(Expression),
+    /// `try { body } catch (name) { handler } finally { cleanup }`, where at least one of the
+    /// catch and finally clauses is present. The blocks are `BlockStatement`s.
+    Try(Box<Statement>, Option<(String, Box<Statement>)>, Option<Box<Statement>>),
+    /// Literal text of a template, written to the output as it is
+    Text(String),
 }
 
 #[derive(Debug)]
 pub struct Program {
     statements: Vec<Box<Statement>>,
+    /// Where each node starts, in the order the parser completes them
+    positions: Vec<Position>,
 }
 
 impl Program {
     pub fn new() -> Program {
-        Program{ statements: Vec::new() }
+        Program{ statements: Vec::new(), positions: Vec::new() }
     }
     pub fn push(&mut self, statement: Statement) {
         self.statements.push(Box::new(statement));
@@ -48,4 +77,118 @@ impl Program {
     pub fn statements(&self) -> &Vec<Box<Statement>> {
         &self.statements
     }
+
+    /// Sets where the nodes of the program start, children before their parent and siblings from
+    /// left to right, as a parser completes them.
+    pub fn set_positions(&mut self, positions: Vec<Position>) {
+        self.positions = positions;
+    }
+
+    /// Maps the nodes of the program to where they start in the source.
+    pub fn positions(&self) -> Positions {
+        let parsed = !self.positions.is_empty();
+        let mut positions = Positions{ statements: HashMap::new(), expressions: HashMap::new(), parsed };
+        let mut recorded = self.positions.iter().cloned();
+        for statement in &self.statements {
+            positions.add_statement(statement, &mut recorded);
+        }
+        // Walking the nodes in another order than the parser completes them in would move spans about
+        debug_assert!(!parsed || (recorded.next().is_none() && positions.len() == self.positions.len()),
+            "{} positions recorded for {} nodes", self.positions.len(), positions.len());
+        positions
+    }
+}
+
+/// Start positions of the nodes of a program, looked up by reference. Nodes are told apart by
+/// their address, which doesn't change as long as the program isn't.
+pub struct Positions {
+    statements: HashMap<*const Statement, Position>,
+    expressions: HashMap<*const Expression, Position>,
+    /// Whether the program was parsed, so that all its nodes have positions
+    parsed: bool,
+}
+
+impl Positions {
+    /// Where a statement starts, or the default position for programs that weren't parsed.
+    /// Statements of parsed programs are all expected to be found.
+    pub fn statement(&self, statement: &Statement) -> Position {
+        let pos = self.statements.get(&(statement as *const Statement)).cloned();
+        debug_assert!(pos.is_some() || !self.parsed, "no position recorded for {:?}", statement);
+        pos.unwrap_or_default()
+    }
+
+    pub fn expression(&self, e: &Expression) -> Position {
+        let pos = self.expressions.get(&(e as *const Expression)).cloned();
+        debug_assert!(pos.is_some() || !self.parsed, "no position recorded for {:?}", e);
+        pos.unwrap_or_default()
+    }
+
+    /// The number of nodes with a position.
+    pub(crate) fn len(&self) -> usize {
+        self.statements.len() + self.expressions.len()
+    }
+
+    fn add_statement<I: Iterator<Item = Position>>(&mut self, statement: &Statement, recorded: &mut I) {
+        match statement {
+            Statement::Let(_, e) | Statement::Ret(e) | Statement::ExprStatement(e) | Statement::Throw(e) =>
+                self.add_expression(e, recorded),
+            Statement::BlockStatement(statements) => for statement in statements {
+                self.add_statement(statement, recorded);
+            },
+            Statement::Try(body, handler, finally) => {
+                self.add_statement(body, recorded);
+                if let Some((_, handler)) = handler {
+                    self.add_statement(handler, recorded);
+                }
+                if let Some(finally) = finally {
+                    self.add_statement(finally, recorded);
+                }
+            },
+            Statement::Text(_) => {},
+        }
+        if let Some(pos) = recorded.next() {
+            self.statements.insert(statement, pos);
+        }
+    }
+
+    fn add_expression<I: Iterator<Item = Position>>(&mut self, e: &Expression, recorded: &mut I) {
+        match e {
+            Expression::Plus(left, right) | Expression::Minus(left, right) | Expression::Div(left, right)
+            | Expression::Mul(left, right) | Expression::Eq(left, right) | Expression::Ne(left, right)
+            | Expression::Lt(left, right) | Expression::Gt(left, right) | Expression::Index(left, right) => {
+                self.add_expression(left, recorded);
+                self.add_expression(right, recorded);
+            },
+            Expression::Neg(operand) | Expression::Not(operand) | Expression::Spread(operand) => self.add_expression(operand, recorded),
+            Expression::If(condition, consequence, alternative) => {
+                self.add_expression(condition, recorded);
+                self.add_statement(consequence, recorded);
+                self.add_statement(alternative, recorded);
+            },
+            Expression::FnDecl(params, body) => {
+                for default in params.iter().filter_map(|param| param.default.as_ref()) {
+                    self.add_expression(default, recorded);
+                }
+                self.add_statement(body, recorded);
+            },
+            Expression::Call(function, args) => {
+                self.add_expression(function, recorded);
+                for arg in args {
+                    self.add_expression(arg, recorded);
+                }
+            },
+            Expression::Array(elems) => for elem in elems {
+                self.add_expression(elem, recorded);
+            },
+            Expression::Hash(pairs) => for (key, value) in pairs {
+                self.add_expression(key, recorded);
+                self.add_expression(value, recorded);
+            },
+            Expression::Int(_) | Expression::Ident(_) | Expression::True | Expression::False
+            | Expression::Null | Expression::String(_) => {},
+        }
+        if let Some(pos) = recorded.next() {
+            self.expressions.insert(e, pos);
+        }
+    }
 }
@@ -1,21 +1,454 @@
 extern crate monkeylang;
 
 use std::env;
+use std::fs;
+use std::io;
+use std::io::{ Read, Write };
+use std::process;
+use monkeylang::check;
+use monkeylang::check::Severity;
+use monkeylang::eval;
+use monkeylang::lexer::{ Lexer, tokenize };
+use monkeylang::lsp;
+use monkeylang::parser;
+use monkeylang::parser::{ Parser, ParseError };
+use monkeylang::ast::Program;
 use monkeylang::repl;
+use monkeylang::run;
 use monkeylang::server;
+use monkeylang::server::ServerConfig;
+
+const USAGE: &str = "\
+Usage: monkeylang [COMMAND] [ARGS...]
+
+Commands:
+  repl      Start an interactive session; the default without a command
+  run       Run a program
+  serve     Serve the pages and files in a directory over HTTP
+  fmt       Format programs in the canonical style
+  check     Look for mistakes in programs
+  tokens    Show the tokens a program is made of
+  ast       Show how a program is parsed
+  lsp       Serve editors over the Language Server Protocol
+
+Options:
+  -h, --help     Show this help, or that of a command after it
+  -V, --version  Show the version
+
+Run `monkeylang COMMAND --help` to see how a command is used.";
+
+const REPL_USAGE: &str = "\
+Usage: monkeylang repl
+
+Starts an interactive session. Type :help in it for the commands it supports.";
+
+const RUN_USAGE: &str = "\
+Usage: monkeylang run [FILE] [ARGS...]
+
+Runs the program in FILE, or the one read from stdin if FILE is - or missing.
+ARGS are bound to `args` as an array of strings.
+Exits with 1 if the program fails and 2 if it can't be read.";
+
+const SERVE_USAGE: &str = "\
+Usage: monkeylang serve [INTERFACE [PORT]] [--OPTION VALUE]...
+
+Serves the files under a directory, running .ml pages as templates.
+
+Options:
+  --config FILE            Read options from FILE, one `option = value` per line
+  --interface ADDRESS      Address to listen on [localhost]
+  --port PORT              Port to listen on [80]
+  --root DIR               Directory to serve [public]
+  --index FILES            Files served for directories [index.ml]
+  --routes FILE            Routes mapping methods and paths onto scripts
+  --list-directories BOOL  List directories without an index file [false]
+  --status-endpoints BOOL  Answer /_health and /_metrics [true]
+  --tls-cert FILE          Certificate chain to serve HTTPS with, as PEM
+  --tls-key FILE           Private key of the certificate, as PEM
+  --threads N              Worker threads [8]
+  --queue-size N           Connections that may wait for a worker [128]
+  --overflow MODE          block or reject connections once the queue is full [block]
+  --max-header-size BYTES  [40960]
+  --max-body-size BYTES    [1048576]
+  --read-timeout TIME      e.g. 500ms, 30s or 2m, or none [30s]
+  --write-timeout TIME     [30s]
+  --shutdown-timeout TIME  How long requests may take to finish on shutdown [30s]
+  --max-steps N            Evaluation steps a page may take, or none [10000000]
+  --max-time TIME          [10s]
+  --max-depth N            Nested calls a page may make [1000]
+  --max-size N             Size of the values a page may build [1048576]
+  --log-level LEVEL        off, error, info or debug [info]
+  --log-format FORMAT      common, combined or json [common]
+  --access-log TARGET      off, stdout, stderr or a file [stdout]";
+
+const FMT_USAGE: &str = "\
+Usage: monkeylang fmt [--check] [FILE...]
+
+Rewrites each FILE in the canonical style, or formats stdin to stdout without any.
+Programs that don't parse are reported and left alone.
+
+Options:
+  --check  Only list the files that aren't formatted, exiting with 1 if there are any";
+
+const CHECK_USAGE: &str = "\
+Usage: monkeylang check [--template] FILE...
+
+Reports syntax errors in each FILE, along with likely mistakes: bindings never used, names not
+defined, calls with the wrong number of arguments, statements after a return and names shadowing
+others. Exits with 1 if there are errors; warnings alone don't fail the check.
+
+Options:
+  --template  Read the files as templates served by `serve`, rather than programs";
+
+const TOKENS_USAGE: &str = "\
+Usage: monkeylang tokens [FILE]
+
+Shows the tokens of the program in FILE, or stdin, with the line and column they start at.";
+
+const AST_USAGE: &str = "\
+Usage: monkeylang ast [--template] [FILE]
+
+Shows the syntax tree of the program in FILE, or stdin.
+
+Options:
+  --template  Read the file as a template served by `serve`, rather than a program";
+
+const LSP_USAGE: &str = "\
+Usage: monkeylang lsp
+
+Speaks the Language Server Protocol over stdin and stdout, for editors to show syntax errors and
+the mistakes `check` finds, go to definitions, find references, describe builtins, complete names
+and format programs. Files with <% tags are read as templates, the rest as programs.";
 
 fn main() {
-    let mut args = env::args();
-    args.next();
-    match args.next().as_ref() {
-        Some(s) if s == "-serve" => {
-            let interface = match args.next().as_ref() {
-                Some(s) => s.clone(),
-                _ => String::from("localhost"),
-            };
-            let port = args.next().as_ref().and_then(|i| i.parse().ok()).unwrap_or(80);
-            server::serve(interface, port);
-        },
-        _ => repl::start_repl(),
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => ("repl", &args[..]),
+    };
+    // Arguments after the file given to `run` are the program's own
+    let wants_help = match command {
+        "run" => args.first().is_some_and(|arg| arg == "--help" || arg == "-h"),
+        _ => args.iter().any(|arg| arg == "--help" || arg == "-h"),
+    };
+    let code = match command {
+        "-h" | "--help" | "help" => help(args.first().map_or("", String::as_str)),
+        "-V" | "--version" => {
+            println!("monkeylang {}", env!("CARGO_PKG_VERSION"));
+            0
+        },
+        _ if wants_help => help(command),
+        "repl" => match args.first() {
+            Some(arg) => usage_error(&format!("Unexpected argument {}", arg), "repl"),
+            None => {
+                eval::with_stack(repl::start_repl);
+                0
+            },
+        },
+        "run" => run_script(args),
+        // `-serve` is how the server used to be started
+        "serve" | "-serve" => serve(args),
+        "fmt" => fmt(args),
+        "check" => check(args),
+        "tokens" => tokens(args),
+        "ast" => ast(args),
+        "lsp" => lsp(args),
+        _ => usage_error(&format!("Unknown command {}", command), ""),
+    };
+    process::exit(code);
+}
+
+fn help(command: &str) -> i32 {
+    let usage = match command {
+        "" => USAGE,
+        "repl" => REPL_USAGE,
+        "run" => RUN_USAGE,
+        "serve" => SERVE_USAGE,
+        "fmt" => FMT_USAGE,
+        "check" => CHECK_USAGE,
+        "tokens" => TOKENS_USAGE,
+        "ast" => AST_USAGE,
+        "lsp" => LSP_USAGE,
+        _ => return usage_error(&format!("Unknown command {}", command), ""),
+    };
+    println!("{}", usage);
+    0
+}
+
+/// Reports a mistake in the arguments, pointing to the help of `command`. Returns the exit code for it.
+fn usage_error(message: &str, command: &str) -> i32 {
+    let help = if command.is_empty() { String::from("--help") } else { format!("{} --help", command) };
+    eprintln!("{}\nRun `monkeylang {}` for usage.", message, help);
+    2
+}
+
+/// Splits arguments into the `--flags` out of `known` that were given, and the rest.
+fn parse_flags<'a>(args: &'a [String], known: &[&str], command: &str) -> Result<(Vec<&'a str>, Vec<&'a str>), i32> {
+    let mut flags = Vec::new();
+    let mut rest = Vec::new();
+    for arg in args {
+        if arg.starts_with("--") {
+            if !known.contains(&arg.as_str()) {
+                return Err(usage_error(&format!("Unknown option {}", arg), command));
+            }
+            flags.push(arg.as_str());
+        } else {
+            rest.push(arg.as_str());
+        }
+    }
+    Ok((flags, rest))
+}
+
+/// Reads a file, or stdin for `-`. Errors are reported, returning the exit code for them.
+fn read_input(file: &str) -> Result<String, i32> {
+    let code = if file == "-" {
+        let mut code = String::new();
+        io::stdin().read_to_string(&mut code).map(|_| code)
+    } else {
+        fs::read_to_string(file)
+    };
+    code.map_err(|err| {
+        eprintln!("Could not read {}: {}", file, err);
+        2
+    })
+}
+
+fn display_name(file: &str) -> &str {
+    if file == "-" { "<stdin>" } else { file }
+}
+
+fn parse(code: &str, template: bool) -> Result<Program, ParseError> {
+    if template {
+        server::compile_template(code)
+    } else {
+        Parser::new(&mut Lexer::new(String::from(code))).parse_program()
+    }
+}
+
+/// Parses a program, or a template, and hands it to `f`, all on the large evaluation stack so that
+/// what `run` accepts doesn't overflow the main thread's. A first line starting with `#!` is left
+/// out of programs, as `run` does.
+fn with_parsed<T: Send>(code: &str, template: bool, f: impl FnOnce(Result<Program, ParseError>) -> T + Send) -> T {
+    let code = if template { code } else { run::strip_shebang(code) };
+    eval::with_stack(|| f(parse(code, template)))
+}
+
+/// Takes the single optional file argument of a command, which defaults to stdin.
+fn single_file<'a>(files: &[&'a str], command: &str) -> Result<&'a str, i32> {
+    match files {
+        [] => Ok("-"),
+        [file] => Ok(file),
+        [_, extra, ..] => Err(usage_error(&format!("Unexpected argument {}", extra), command)),
+    }
+}
+
+fn run_script(args: &[String]) -> i32 {
+    let (file, args) = match args.split_first() {
+        Some((file, args)) => (file.as_str(), args),
+        None => ("-", args),
+    };
+    let code = match read_input(file) {
+        Ok(code) => code,
+        Err(code) => return code,
+    };
+    let mut stdout = io::stdout();
+    let result = eval::with_stack(|| run::run(&code, args, &mut stdout));
+    let _ = stdout.flush();
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}: {}", display_name(file), err);
+            for frame in &err.trace {
+                eprintln!("    in {}", frame);
+            }
+            if err.omitted > 0 {
+                eprintln!("    ... {} more", err.omitted);
+            }
+            1
+        },
+    }
+}
+
+fn serve(args: &[String]) -> i32 {
+    match ServerConfig::from_args(args) {
+        Ok(config) => match server::serve(config) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("Could not serve: {}", err);
+                1
+            },
+        },
+        Err(err) => usage_error(&err, "serve"),
+    }
+}
+
+fn fmt(args: &[String]) -> i32 {
+    let (flags, mut files) = match parse_flags(args, &["--check"], "fmt") {
+        Ok(parsed) => parsed,
+        Err(code) => return code,
+    };
+    let check = !flags.is_empty();
+    if files.is_empty() {
+        files.push("-");
+    }
+    let mut code = 0;
+    for file in files {
+        let contents = match read_input(file) {
+            Ok(contents) => contents,
+            Err(read_error) => {
+                code = code.max(read_error);
+                continue;
+            },
+        };
+        let formatted = match with_parsed(&contents, false, |program| program.map(|program| parser::format(&program))) {
+            Ok(formatted) => formatted,
+            Err(err) => {
+                eprintln!("{}: {}", display_name(file), err);
+                code = code.max(1);
+                continue;
+            },
+        };
+        let shebang = &contents[..contents.len() - run::strip_shebang(&contents).len()];
+        let formatted = if shebang.is_empty() { formatted } else { format!("{}\n{}", shebang, formatted) };
+        if check {
+            if formatted != contents {
+                println!("{}", display_name(file));
+                code = code.max(1);
+            }
+        } else if file == "-" {
+            print!("{}", formatted);
+        } else if formatted != contents {
+            if let Err(err) = fs::write(file, formatted) {
+                eprintln!("Could not write {}: {}", file, err);
+                code = code.max(2);
+            }
+        }
+    }
+    code
+}
+
+fn check(args: &[String]) -> i32 {
+    let (flags, files) = match parse_flags(args, &["--template"], "check") {
+        Ok(parsed) => parsed,
+        Err(code) => return code,
+    };
+    if files.is_empty() {
+        return usage_error("No files to check", "check");
+    }
+    let template = !flags.is_empty();
+    let globals = if template { server::GLOBALS } else { run::GLOBALS };
+    let mut code = 0;
+    for file in files {
+        let findings = read_input(file)
+            .map(|contents| with_parsed(&contents, template, |program| program.map(|program| check::check(&program, globals))));
+        match findings {
+            Ok(Ok(findings)) => for finding in findings {
+                eprintln!("{}: {}", display_name(file), finding);
+                if finding.lint.severity() == Severity::Error {
+                    code = code.max(1);
+                }
+            },
+            Ok(Err(err)) => {
+                eprintln!("{}: {}", display_name(file), err);
+                code = code.max(1);
+            },
+            Err(read_error) => code = code.max(read_error),
+        }
+    }
+    code
+}
+
+fn tokens(args: &[String]) -> i32 {
+    let file = match parse_flags(args, &[], "tokens").and_then(|(_, files)| single_file(&files, "tokens")) {
+        Ok(file) => file,
+        Err(code) => return code,
+    };
+    match read_input(file) {
+        Ok(code) => {
+            for (token, pos) in tokenize(run::strip_shebang(&code)) {
+                println!("{}:{}\t{:?}", pos.line, pos.col, token);
+            }
+            0
+        },
+        Err(code) => code,
+    }
+}
+
+fn ast(args: &[String]) -> i32 {
+    let (flags, file) = match parse_flags(args, &["--template"], "ast")
+        .and_then(|(flags, files)| single_file(&files, "ast").map(|file| (flags, file))) {
+        Ok(parsed) => parsed,
+        Err(code) => return code,
+    };
+    let code = match read_input(file) {
+        Ok(code) => code,
+        Err(code) => return code,
+    };
+    with_parsed(&code, !flags.is_empty(), |program| match program {
+        Ok(program) => {
+            for statement in program.statements() {
+                println!("{:#?}", statement);
+            }
+            0
+        },
+        Err(err) => {
+            eprintln!("{}: {}", display_name(file), err);
+            1
+        },
+    })
+}
+
+fn lsp(args: &[String]) -> i32 {
+    if let Some(arg) = args.first() {
+        return usage_error(&format!("Unexpected argument {}", arg), "lsp");
+    }
+    // Documents are parsed and checked as they come, which recurses as deeply as they nest
+    match eval::with_stack(|| lsp::serve(&mut io::stdin().lock(), &mut io::stdout().lock())) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ run_script, fmt, check, tokens, ast };
+    use std::env;
+    use std::fs;
+    use std::slice;
+
+    #[test]
+    fn test_run_script() {
+        let file = env::temp_dir().join(format!("monkeylang-run-{}.ml", std::process::id()));
+        fs::write(&file, "print(1 / 0)").unwrap();
+        // An error in the program rather than a panic of the interpreter
+        assert_eq!(run_script(&[file.to_string_lossy().into_owned()]), 1);
+        fs::write(&file, "let x = 1;").unwrap();
+        assert_eq!(run_script(&[file.to_string_lossy().into_owned()]), 0);
+        fs::remove_file(&file).unwrap();
+    }
+
+    /// Writes an executable script nesting `depth` levels deep, returning its path as an argument.
+    fn deep_script(name: &str, depth: usize) -> String {
+        let file = env::temp_dir().join(format!("monkeylang-{}-{}.ml", name, std::process::id()));
+        fs::write(&file, format!("#!/usr/bin/env monkeylang\nlet x = {}1{};\n", "(".repeat(depth), ")".repeat(depth))).unwrap();
+        file.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let file = deep_script("parse", 10000);
+        assert_eq!(run_script(slice::from_ref(&file)), 0);
+        assert_eq!(tokens(slice::from_ref(&file)), 0);
+        assert_eq!(ast(slice::from_ref(&file)), 0);
+        // Only warns that x is never used
+        assert_eq!(check(slice::from_ref(&file)), 0);
+        assert_eq!(fmt(slice::from_ref(&file)), 0);
+        assert!(fs::read_to_string(&file).unwrap().starts_with("#!/usr/bin/env monkeylang\nlet x = "));
+        // Formatting it again finds nothing to change
+        assert_eq!(fmt(&[String::from("--check"), file.clone()]), 0);
+        fs::remove_file(&file).unwrap();
     }
 }
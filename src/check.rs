@@ -0,0 +1,452 @@
+use ast::{ Expression, Statement, Param, Program, Positions };
+use eval::{ BUILTINS, arity, arity_mismatch };
+use lexer::Position;
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+use std::fmt::{ Display, Formatter };
+
+/// What a finding is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lint {
+    /// A `let` binding that is never used
+    Unused,
+    /// A name that isn't bound where it is used, which evaluates to `null`
+    Undefined,
+    /// A call with more or fewer arguments than the function takes
+    Arity,
+    /// A statement following a `return` or `throw` in the same block
+    Unreachable,
+    /// A `let` or parameter hiding a binding of an enclosing scope or a builtin
+    Shadowed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    /// Almost certainly a bug
+    Error,
+}
+
+impl Lint {
+    pub fn severity(self) -> Severity {
+        match self {
+            Lint::Undefined | Lint::Arity => Severity::Error,
+            Lint::Unused | Lint::Unreachable | Lint::Shadowed => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub lint: Lint,
+    pub pos: Position,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let severity = match self.lint.severity() {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        f.write_str(&format!("line {}, column {}: {}: {}", self.pos.line, self.pos.col, severity, self.message))
+    }
+}
+
+/// A name bound by `let` or as a parameter, along with where it is used.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// Where the `let` statement or the function taking the parameter starts, or for the name a
+    /// `catch` binds, its block
+    pub pos: Position,
+    /// Set for parameters and the names `catch` binds, which needn't be used
+    pub param: bool,
+    /// The parameters of the function bound, for bindings of function literals
+    pub params: Option<Vec<Param>>,
+    /// Where the name is used to refer to this binding. Uses from functions count for all the
+    /// bindings of the name in the scopes enclosing them.
+    pub references: Vec<Position>,
+}
+
+/// The fewest and most arguments a function takes, `None` meaning any number.
+type Arity = (usize, Option<usize>);
+
+struct Binding {
+    name: String,
+    pos: Position,
+    /// Index of the symbol of the binding
+    symbol: usize,
+    /// Set for bindings of function literals
+    arity: Option<Arity>,
+    param: bool,
+    used: bool,
+}
+
+/// The bindings of the program or of a function body. Blocks share the scope they are in, as
+/// they do when evaluated.
+struct Scope {
+    /// Names bound anywhere in the scope
+    declared: HashSet<String>,
+    /// Bindings made so far, in order
+    bindings: Vec<Binding>,
+    /// Names used by functions defined in the scope, and where. They may be called once any
+    /// binding of the name has been made, so all of them count as used.
+    deferred: HashMap<String, Vec<Position>>,
+}
+
+struct Checker<'a> {
+    positions: Positions,
+    globals: &'a [&'a str],
+    builtins: HashMap<&'static str, Arity>,
+    scopes: Vec<Scope>,
+    findings: Vec<Finding>,
+    symbols: Vec<Symbol>,
+}
+
+/// Looks for likely mistakes in a program, returning them in the order they appear in.
+/// `globals` are the names bound before it runs besides the builtins, such as `args`.
+///
+/// Functions are called with the scope of their caller, so names a function uses may be bound
+/// after it is defined. They are only reported if they aren't bound anywhere in the scopes
+/// enclosing it.
+pub fn check(program: &Program, globals: &[&str]) -> Vec<Finding> {
+    let mut findings = analyze(program, globals).findings;
+    findings.sort_by_key(|finding| finding.pos);
+    findings
+}
+
+/// Lists the bindings of a program in the order they are made, resolving names as `check` does.
+pub fn symbols(program: &Program, globals: &[&str]) -> Vec<Symbol> {
+    let mut symbols = analyze(program, globals).symbols;
+    for symbol in &mut symbols {
+        symbol.references.sort();
+    }
+    symbols
+}
+
+fn analyze<'a>(program: &Program, globals: &'a [&'a str]) -> Checker<'a> {
+    let statements = program.statements().iter().map(|statement| &**statement).collect::<Vec<&Statement>>();
+    let mut checker = Checker {
+        positions: program.positions(),
+        globals,
+        builtins: BUILTINS.iter().map(|&(name, min, max)| (name, (min, max))).collect(),
+        scopes: Vec::new(),
+        findings: Vec::new(),
+        symbols: Vec::new(),
+    };
+    let mut declared = HashSet::new();
+    for statement in &statements {
+        declare_statement(statement, &mut declared);
+    }
+    checker.scopes.push(Scope{ declared, bindings: Vec::new(), deferred: HashMap::new() });
+    // A `return` outside of functions doesn't stop the program, so nothing after it is unreachable
+    checker.sequence(&statements, false);
+    checker.leave();
+    checker
+}
+
+impl<'a> Checker<'a> {
+    fn report(&mut self, lint: Lint, pos: Position, message: String) {
+        self.findings.push(Finding{ lint, pos, message });
+    }
+
+    fn sequence(&mut self, statements: &[&Statement], in_block: bool) {
+        let end = statements.iter().position(|statement| match statement {
+            Statement::Ret(_) => in_block,
+            Statement::Throw(_) => true,
+            _ => false,
+        });
+        if let Some(i) = end.filter(|i| i + 1 < statements.len()) {
+            let after = if matches!(statements[i], Statement::Throw(_)) { "throw" } else { "return" };
+            let pos = self.positions.statement(statements[i + 1]);
+            self.report(Lint::Unreachable, pos, format!("unreachable statement after {}", after));
+        }
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Let(name, e) => {
+                self.expression(e);
+                let params = match e {
+                    Expression::FnDecl(params, _) => Some(params.clone()),
+                    _ => None,
+                };
+                let pos = self.positions.statement(statement);
+                self.bind(name, pos, params, false);
+            },
+            Statement::Ret(e) | Statement::ExprStatement(e) | Statement::Throw(e) => self.expression(e),
+            Statement::BlockStatement(statements) => self.sequence(&statements.iter().collect::<Vec<&Statement>>(), true),
+            Statement::Try(body, handler, finally) => {
+                self.statement(body);
+                if let Some((name, handler)) = handler {
+                    let pos = self.positions.statement(handler);
+                    self.bind(name, pos, None, true);
+                    self.statement(handler);
+                }
+                if let Some(finally) = finally {
+                    self.statement(finally);
+                }
+            },
+            Statement::Text(_) => {},
+        }
+    }
+
+    fn expression(&mut self, e: &Expression) {
+        match e {
+            Expression::Ident(name) => {
+                let pos = self.positions.expression(e);
+                self.resolve(name, pos);
+            },
+            Expression::FnDecl(params, body) => self.function(params, body, self.positions.expression(e)),
+            Expression::If(condition, consequence, alternative) => {
+                self.expression(condition);
+                self.statement(consequence);
+                self.statement(alternative);
+            },
+            Expression::Call(function, args) => {
+                let (name, arity) = match &**function {
+                    Expression::Ident(name) => (name.as_str(), self.resolve(name, self.positions.expression(function))),
+                    Expression::FnDecl(params, body) => {
+                        self.function(params, body, self.positions.expression(function));
+                        ("the function", Some(arity(params)))
+                    },
+                    other => {
+                        self.expression(other);
+                        ("", None)
+                    },
+                };
+                for arg in args {
+                    self.expression(arg);
+                }
+                // How many arguments spread arrays make is only known once they are evaluated
+                let spread = args.iter().any(|arg| matches!(arg, Expression::Spread(_)));
+                if let Some(message) = arity.filter(|_| !spread).and_then(|arity| arity_mismatch(name, arity, args.len())) {
+                    self.report(Lint::Arity, self.positions.expression(e), message);
+                }
+            },
+            _ => for operand in operands(e) {
+                self.expression(operand);
+            },
+        }
+    }
+
+    fn function(&mut self, params: &[Param], body: &Statement, pos: Position) {
+        let mut declared = params.iter().map(|param| param.name.clone()).collect::<HashSet<String>>();
+        declare_statement(body, &mut declared);
+        self.scopes.push(Scope{ declared, bindings: Vec::new(), deferred: HashMap::new() });
+        for param in params {
+            // Defaults are evaluated in the scope of the call, once the parameters before them are bound
+            if let Some(default) = &param.default {
+                self.expression(default);
+            }
+            self.bind(&param.name, pos, None, true);
+        }
+        self.statement(body);
+        self.leave();
+    }
+
+    fn bind(&mut self, name: &str, pos: Position, params: Option<Vec<Param>>, param: bool) {
+        let (scope, enclosing) = self.scopes.split_last_mut().unwrap();
+        // Binding a name again in the same scope replaces it, there being no other way to assign
+        if !scope.bindings.iter().any(|binding| binding.name == name) {
+            if enclosing.iter().any(|scope| scope.declared.contains(name)) {
+                self.findings.push(Finding{ lint: Lint::Shadowed, pos, message: format!("{} shadows a binding of an enclosing scope", name) });
+            } else if self.builtins.contains_key(name) || self.globals.contains(&name) {
+                self.findings.push(Finding{ lint: Lint::Shadowed, pos, message: format!("{} shadows a builtin", name) });
+            }
+        }
+        let arity = params.as_deref().map(arity);
+        scope.bindings.push(Binding{ name: String::from(name), pos, symbol: self.symbols.len(), arity, param, used: false });
+        self.symbols.push(Symbol{ name: String::from(name), pos, param, params, references: Vec::new() });
+    }
+
+    /// Marks the binding a name refers to as used, returning the arity of the function bound to it if known.
+    fn resolve(&mut self, name: &str, pos: Position) -> Option<Arity> {
+        let (scope, enclosing) = self.scopes.split_last_mut().unwrap();
+        if let Some(binding) = scope.bindings.iter_mut().rev().find(|binding| binding.name == name) {
+            binding.used = true;
+            self.symbols[binding.symbol].references.push(pos);
+            return binding.arity;
+        }
+        // The function will be called later on, by when any binding in the scopes enclosing it may have been made
+        if let Some(outer) = enclosing.iter_mut().rev().find(|scope| scope.declared.contains(name)) {
+            outer.deferred.entry(String::from(name)).or_default().push(pos);
+            let mut arities = outer.bindings.iter().filter(|binding| binding.name == name).map(|binding| binding.arity);
+            return match arities.next() {
+                Some(arity) if arities.all(|other| other == arity) => arity,
+                _ => None,
+            };
+        }
+        if let Some(arity) = self.builtins.get(name) {
+            return Some(*arity);
+        }
+        if !self.globals.contains(&name) {
+            let message = if scope.declared.contains(name) {
+                format!("{} is used before it is bound", name)
+            } else {
+                format!("{} is not defined", name)
+            };
+            self.report(Lint::Undefined, pos, message);
+        }
+        None
+    }
+
+    fn leave(&mut self) {
+        let scope = self.scopes.pop().unwrap();
+        for binding in scope.bindings {
+            let deferred = scope.deferred.get(&binding.name);
+            if let Some(uses) = deferred {
+                self.symbols[binding.symbol].references.extend(uses);
+            }
+            // Names starting with an underscore are left unused on purpose
+            if !binding.used && !binding.param && deferred.is_none() && !binding.name.starts_with('_') {
+                self.report(Lint::Unused, binding.pos, format!("{} is never used", binding.name));
+            }
+        }
+    }
+}
+
+/// Adds the names a statement binds to `names`, leaving out those of functions in it.
+fn declare_statement(statement: &Statement, names: &mut HashSet<String>) {
+    match statement {
+        Statement::Let(name, e) => {
+            names.insert(name.clone());
+            declare_expression(e, names);
+        },
+        Statement::Ret(e) | Statement::ExprStatement(e) | Statement::Throw(e) => declare_expression(e, names),
+        Statement::BlockStatement(statements) => for statement in statements {
+            declare_statement(statement, names);
+        },
+        Statement::Try(body, handler, finally) => {
+            declare_statement(body, names);
+            if let Some((name, handler)) = handler {
+                names.insert(name.clone());
+                declare_statement(handler, names);
+            }
+            if let Some(finally) = finally {
+                declare_statement(finally, names);
+            }
+        },
+        Statement::Text(_) => {},
+    }
+}
+
+fn declare_expression(e: &Expression, names: &mut HashSet<String>) {
+    match e {
+        Expression::If(condition, consequence, alternative) => {
+            declare_expression(condition, names);
+            declare_statement(consequence, names);
+            declare_statement(alternative, names);
+        },
+        // Functions have a scope of their own
+        Expression::FnDecl(..) => {},
+        _ => for operand in operands(e) {
+            declare_expression(operand, names);
+        },
+    }
+}
+
+/// The expressions an expression is made of, leaving out the statements of `if` and functions.
+fn operands(e: &Expression) -> Vec<&Expression> {
+    match e {
+        Expression::Plus(left, right) | Expression::Minus(left, right) | Expression::Div(left, right)
+        | Expression::Mul(left, right) | Expression::Eq(left, right) | Expression::Ne(left, right)
+        | Expression::Lt(left, right) | Expression::Gt(left, right) | Expression::Index(left, right) => vec![left, right],
+        Expression::Neg(operand) | Expression::Not(operand) | Expression::Spread(operand) => vec![operand],
+        Expression::If(condition, _, _) => vec![condition],
+        Expression::Call(function, args) => Some(&**function).into_iter().chain(args).collect(),
+        Expression::Array(elems) => elems.iter().collect(),
+        Expression::Hash(pairs) => pairs.iter().flat_map(|(key, value)| vec![key, value]).collect(),
+        Expression::FnDecl(..) | Expression::Int(_) | Expression::Ident(_) | Expression::True
+        | Expression::False | Expression::Null | Expression::String(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ check, symbols, Lint };
+    use lexer::{ Lexer, Position };
+    use parser::Parser;
+
+    fn findings(code: &str) -> Vec<(Lint, usize, usize, String)> {
+        let program = Parser::new(&mut Lexer::new(String::from(code))).parse_program().unwrap();
+        check(&program, &["args"]).into_iter()
+            .map(|finding| (finding.lint, finding.pos.line, finding.pos.col, finding.message))
+            .collect()
+    }
+
+    fn finding(lint: Lint, line: usize, col: usize, message: &str) -> (Lint, usize, usize, String) {
+        (lint, line, col, String::from(message))
+    }
+
+    #[test]
+    fn test_clean() {
+        let code = "let fib = fn(x) { if (x < 2) { return x; } fib(x - 1) + fib(x - 2) };\n\
+            let show = fn() println(greeting + len(args));\n\
+            let greeting = \"fib: \";\n\
+            show();\n\
+            let _ignored = map([1, 2], fn(x) x * 2);\n\
+            let n = 1; let n = n + 1; println(fib(n));\n\
+            let opt = fn(a, b = a, ...rest) a + b + len(rest); opt(1); opt(...[1, 2, 3]);\n\
+            try { throw \"oops\" } catch (e) {} finally { opt(1) }";
+        assert_eq!(findings(code), vec![]);
+    }
+
+    #[test]
+    fn test_findings() {
+        let code = "let unused = 1;\n\
+            let add = fn(a, b) { a + b };\n\
+            add(1, 2, 3) + len() + print(1, 2, 3);\n\
+            println(undefined + later);\n\
+            let later = fn() { return 1; 2 };\n\
+            let shadow = fn(add) { let len = 1; len + add };\n\
+            fn(x) { x }(1, 2);\n\
+            shadow(1) + later();\n\
+            let opt = fn(a, b = 1) { a + b }; opt(1, 2, 3) + opt(...args, 1, 2, 3);\n\
+            let fail = fn(opt) { try { throw opt; opt } catch (shadow) { shadow } }; throw fail; fail(1);";
+        assert_eq!(findings(code), vec![
+            finding(Lint::Unused, 1, 1, "unused is never used"),
+            finding(Lint::Arity, 3, 1, "add takes 2 arguments but 3 were given"),
+            finding(Lint::Arity, 3, 16, "len takes 1 argument but 0 were given"),
+            finding(Lint::Undefined, 4, 9, "undefined is not defined"),
+            finding(Lint::Undefined, 4, 21, "later is used before it is bound"),
+            finding(Lint::Unreachable, 5, 30, "unreachable statement after return"),
+            finding(Lint::Shadowed, 6, 14, "add shadows a binding of an enclosing scope"),
+            finding(Lint::Shadowed, 6, 24, "len shadows a builtin"),
+            finding(Lint::Arity, 7, 1, "the function takes 1 argument but 2 were given"),
+            finding(Lint::Arity, 9, 35, "opt takes 1 to 2 arguments but 3 were given"),
+            finding(Lint::Shadowed, 10, 12, "opt shadows a binding of an enclosing scope"),
+            finding(Lint::Unreachable, 10, 39, "unreachable statement after throw"),
+            finding(Lint::Shadowed, 10, 60, "shadow shadows a binding of an enclosing scope"),
+            finding(Lint::Unreachable, 10, 86, "unreachable statement after throw"),
+        ]);
+    }
+
+    #[test]
+    fn test_display() {
+        let program = Parser::new(&mut Lexer::new(String::from("\n  x"))).parse_program().unwrap();
+        let found = check(&program, &[]);
+        assert_eq!(found[0].pos, Position{ line: 2, col: 3 });
+        assert_eq!(found[0].to_string(), "line 2, column 3: error: x is not defined");
+    }
+
+    #[test]
+    fn test_symbols() {
+        let code = "let f = fn(a) { a + n };\nlet n = 1;\nf(n);\nlet n = 2;";
+        let program = Parser::new(&mut Lexer::new(String::from(code))).parse_program().unwrap();
+        let found = symbols(&program, &[]).into_iter()
+            .map(|symbol| (symbol.name, symbol.pos.line, symbol.param, symbol.params.map(|params| params.len()),
+                symbol.references.iter().map(|pos| (pos.line, pos.col)).collect::<Vec<(usize, usize)>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![
+            (String::from("a"), 1, true, None, vec![(1, 17)]),
+            (String::from("f"), 1, false, Some(1), vec![(3, 1)]),
+            (String::from("n"), 2, false, None, vec![(1, 21), (3, 3)]),
+            (String::from("n"), 4, false, None, vec![(1, 21)]),
+        ]);
+    }
+}
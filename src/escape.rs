@@ -0,0 +1,81 @@
+/// Escapes text to be placed between HTML tags.
+pub fn html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#x27;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Escapes text to be placed in an HTML attribute value. Everything but ASCII letters and digits
+/// is encoded, so this is also safe for attributes that aren't quoted.
+pub fn attr(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() || !c.is_ascii() {
+            out.push(c);
+        } else {
+            out.push_str(&format!("&#x{:02X};", c as u32));
+        }
+    }
+    out
+}
+
+/// Percent-encodes text to be placed in a URL, such as a query string value.
+pub fn url(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Decodes percent-encoded text, such as the path of a request. Anything that isn't a valid
+/// escape is kept as it is, and bytes that aren't UTF-8 are replaced.
+pub fn decode_url(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(&String::from_utf8_lossy(hex), 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            },
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ html, attr, url, decode_url };
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(html("<script>alert('x' + \"y\")</script> & more"),
+            "&lt;script&gt;alert(&#x27;x&#x27; + &quot;y&quot;)&lt;/script&gt; &amp; more");
+        assert_eq!(attr("a b\" onload=x"), "a&#x20;b&#x22;&#x20;onload&#x3D;x");
+        assert_eq!(url("a b&c=d/é"), "a%20b%26c%3Dd%2F%C3%A9");
+        assert_eq!(html("plain"), "plain");
+        assert_eq!(decode_url(&url("a b&c=d/é")), "a b&c=d/é");
+        assert_eq!(decode_url("100%25%2e%2E%zz%4"), "100%..%zz%4");
+    }
+}
@@ -1,5 +1,7 @@
 use lexer::Lexer;
-use parser::Parser;
+use escape;
+use json;
+use parser::{ Parser, format_params };
 use ast::*;
 use ast::Statement::*;
 use std::collections::HashMap;
@@ -8,30 +10,207 @@ use std::fmt::Formatter;
 use std::fmt::Result;
 use std::io::Write;
 use std::io;
-use std::cmp;
+use std::rc::Rc;
+use std::cell::{ Cell, RefCell };
+use std::sync::Arc;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+/// Signature of the builtins that get access to the interpreter state and output.
+pub type StateFn = fn(&mut State, Vec<Option<Value>>, &mut dyn Write) -> Value;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Int(i32),
     Bool(bool),
     Str(String),
-    FnDecl(Vec<String>, Box<Statement>),
+    /// Markup that is printed as is, even where output is escaped
+    Raw(String),
+    FnDecl(Vec<Param>, Box<Statement>),
     FnBuiltin(String, Box<fn(Vec<Option<Value>>) -> (Box<Value>, Option<String>)>),
+    /// Builtins that need the interpreter, e.g. to evaluate other templates or call the functions they are given
+    FnState(String, Box<StateFn>),
     RetVal(Box<Value>),
     Array(Vec<Value>),
     Hash(HashMap<String, Value>),
+    Error(RuntimeError),
     Null,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    Syntax,
+    LimitExceeded,
+    Template,
+    Json,
+    /// A call with more or fewer arguments than the function takes
+    Arity,
+    /// An operation given a kind of value it doesn't work on
+    Type,
+    /// Calls nested too deeply for the native stack
+    StackOverflow,
+    /// Division by zero, or a result too large for an int
+    Arithmetic,
+    /// Raised by `throw`, of the kind the script gave
+    Thrown(String),
+}
+
+impl ErrorKind {
+    /// Name of the kind of error, as shown to users and given to `catch`.
+    pub fn name(&self) -> &str {
+        match self {
+            ErrorKind::Syntax => "Syntax",
+            ErrorKind::LimitExceeded => "LimitExceeded",
+            ErrorKind::Template => "Template",
+            ErrorKind::Json => "Json",
+            ErrorKind::Arity => "Arity",
+            ErrorKind::Type => "Type",
+            ErrorKind::StackOverflow => "StackOverflow",
+            ErrorKind::Arithmetic => "Arithmetic",
+            ErrorKind::Thrown(kind) => kind,
+        }
+    }
+}
+
+/// Most functions kept in the trace of an error, deep recursion leaving thousands of them.
+pub const MAX_TRACE: usize = 50;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// The functions the error was raised in and left, innermost first, up to `MAX_TRACE` of them
+    pub trace: Vec<String>,
+    /// How many more functions it left past those in `trace`
+    pub omitted: usize,
+    /// What was thrown, for errors raised by `throw`
+    pub value: Option<Box<Value>>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, message: &str) -> RuntimeError {
+        RuntimeError{ kind, message: String::from(message), trace: Vec::new(), omitted: 0, value: None }
+    }
+
+    /// The error raised by `throw value`. A hash gives the kind and message of the error, and its
+    /// trace when one that was caught is thrown again; anything else is the message.
+    fn thrown(value: Value) -> RuntimeError {
+        let (kind, message, trace) = match &value {
+            Hash(hash) => (
+                hash.get("kind").map_or_else(|| String::from("Error"), |kind| kind.to_string()),
+                hash.get("message").map_or_else(|| value.to_string(), |message| message.to_string()),
+                match hash.get("trace") {
+                    Some(Array(trace)) => trace.iter().map(|frame| frame.to_string()).collect(),
+                    _ => Vec::new(),
+                },
+            ),
+            other => (String::from("Error"), other.to_string(), Vec::new()),
+        };
+        let omitted = trace.len().saturating_sub(MAX_TRACE);
+        let trace = trace.into_iter().take(MAX_TRACE).collect();
+        RuntimeError{ kind: ErrorKind::Thrown(kind), message, trace, omitted, value: Some(Box::new(value)) }
+    }
+
+    /// Whether `try` may handle the error. Exceeding a limit stops the script whatever it does.
+    fn catchable(&self) -> bool {
+        self.kind != ErrorKind::LimitExceeded
+    }
+
+    /// What `catch` binds its name to: a hash with the message, kind and trace of the error. A
+    /// hash that was thrown is given back with those added, anything else under `value`.
+    fn caught(self) -> Value {
+        let mut hash = match self.value.map(|value| *value) {
+            Some(Hash(hash)) => hash,
+            Some(value) => vec![(String::from("value"), value)].into_iter().collect(),
+            None => HashMap::new(),
+        };
+        hash.entry(String::from("message")).or_insert(Str(self.message));
+        hash.entry(String::from("kind")).or_insert(Str(String::from(self.kind.name())));
+        let mut trace = self.trace.into_iter().map(Str).collect::<Vec<Value>>();
+        if self.omitted > 0 {
+            trace.push(Str(format!("... {} more", self.omitted)));
+        }
+        hash.insert(String::from("trace"), Array(trace));
+        Hash(hash)
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.write_str(&format!("{}: {}", self.kind.name(), self.message))
+    }
+}
+
 use eval::Value::*;
 
+/// Evaluates to the given result, returning early from the enclosing `eval` if it is an error.
+macro_rules! try_eval {
+    ($e:expr) => {
+        match $e {
+            Some(Error(err)) => return Some(Error(err)),
+            other => other,
+        }
+    };
+}
+
 impl Value {
+    /// Name of the kind of value, as shown to users.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Int(_) => "int",
+            Bool(_) => "bool",
+            Str(_) => "string",
+            Raw(_) => "markup",
+            FnDecl(..) => "function",
+            FnBuiltin(..) | FnState(..) => "builtin",
+            RetVal(v) => v.type_name(),
+            Array(_) => "array",
+            Hash(_) => "hash",
+            Error(_) => "error",
+            Null => "null",
+        }
+    }
+
     fn unret(self) -> Value {
         match self {
             RetVal(v) => *v,
             other => other,
         }
     }
+
+    /// Rough measure of how much memory a value holds: string bytes plus one unit per element.
+    /// Fails with the limit exceeded once the value is found to be larger than `max` or to nest
+    /// deeper than `MAX_NESTING`. Goes through the value without recursing, deep as it may be.
+    fn measure(&self, max: usize) -> ::std::result::Result<usize, &'static str> {
+        measure(vec![(self, 0)], 0, max)
+    }
+}
+
+/// Adds the size of the `pending` values, paired with how deep they sit, to `size`. Stops as soon
+/// as a limit is crossed, so a check never walks more than `max` units of a value.
+fn measure(mut pending: Vec<(&Value, usize)>, mut size: usize, max: usize) -> ::std::result::Result<usize, &'static str> {
+    while let Some((val, depth)) = pending.pop() {
+        if depth >= MAX_NESTING && matches!(val, Array(_) | Hash(_)) {
+            return Err("value nesting limit exceeded");
+        }
+        size += match val {
+            Str(s) | Raw(s) => s.len(),
+            Array(a) => a.len(),
+            Hash(h) => h.keys().map(|k| 1 + k.len()).sum(),
+            RetVal(_) => 0,
+            _ => 1,
+        };
+        if size > max {
+            return Err("value size limit exceeded");
+        }
+        match val {
+            Array(a) => pending.extend(a.iter().map(|v| (v, depth + 1))),
+            Hash(h) => pending.extend(h.values().map(|v| (v, depth + 1))),
+            RetVal(v) => pending.push((v, depth)),
+            _ => {},
+        }
+    }
+    Ok(size)
 }
 
 impl Display for Value {
@@ -39,24 +218,320 @@ impl Display for Value {
         match self {
             Int(i) => f.write_str(&format!("{}", i)),
             Bool(b) => f.write_str(&format!("{}", b)),
-            Str(s) => f.write_str(&format!("{}", s)),
-            FnDecl(pars, _stmt) => f.write_str(&format!("fn({})", pars.join(", "))),
-            FnBuiltin(ident, _) => f.write_str(&format!("builtin {}", ident)),
+            Str(s) | Raw(s) => f.write_str(s),
+            FnDecl(pars, _stmt) => f.write_str(&format!("fn({})", format_params(pars))),
+            FnBuiltin(ident, _) | FnState(ident, _) => f.write_str(&format!("builtin {}", ident)),
             RetVal(v) => v.fmt(f),
             Array(el) => f.write_str(&format!("[{}]", el.iter().map(|el| format!("{}", el)).collect::<Vec<String>>().join(", "))),
             Hash(h) => f.write_str(&format!("{{{}}}", h.iter().map(|(key, value)| format!("{}: {}", key, value)).collect::<Vec<String>>().join(", "))),
+            Error(err) => err.fmt(f),
             Null => f.write_str("null"),
         }
     }
 }
 
+fn escaped(val: &Value) -> String {
+    match val {
+        Raw(s) => s.clone(),
+        other => escape::html(&other.to_string()),
+    }
+}
+
+fn template_error(message: &str) -> Value {
+    Error(RuntimeError::new(ErrorKind::Template, message))
+}
+
+/// Returns what is shared by the page being rendered, with the string argument the builtin `name` was given.
+fn page_arg(state: &State, name: &str, args: &[Option<Value>]) -> ::std::result::Result<(Rc<Page>, String), Value> {
+    let page = match state.page {
+        Some(ref page) => Rc::clone(page),
+        None => return Err(template_error(&format!("{} can only be used in templates", name))),
+    };
+    match args.first() {
+        Some(Some(Str(arg))) => Ok((page, arg.clone())),
+        _ => Err(template_error(&format!("{} expects a string", name))),
+    }
+}
+
+/// `include(path, vars)`: evaluates the template at `path` in a copy of the current scope,
+/// with the entries of the `vars` hash added to it.
+fn include(state: &mut State, args: Vec<Option<Value>>, writer: &mut dyn Write) -> Value {
+    let (page, path) = match page_arg(state, "include", &args) {
+        Ok(arg) => arg,
+        Err(err) => return err,
+    };
+    let program = match page.loader.load(&path) {
+        Ok(program) => program,
+        Err(err) => return Error(err),
+    };
+    let mut scope = state.clone();
+    if let Some(Some(Hash(vars))) = args.get(1) {
+        for (name, value) in vars {
+            scope.set(name, value.clone());
+        }
+    }
+    if let Some(err) = state.budget.enter() {
+        return Error(err);
+    }
+    let rv = program.eval(&mut scope, writer);
+    state.budget.leave();
+    match rv {
+        Some(Error(err)) => Error(err),
+        _ => Null,
+    }
+}
+
+/// `layout(path)`: renders the template at `path` once the current one is done, see `State::render`.
+fn layout(state: &mut State, args: Vec<Option<Value>>, _writer: &mut dyn Write) -> Value {
+    match page_arg(state, "layout", &args) {
+        Ok((page, path)) => {
+            *page.layout.borrow_mut() = Some(path);
+            Null
+        },
+        Err(err) => err,
+    }
+}
+
+/// `block(name, content)`: in a template that declared a layout, defines the block `name`, unless a page
+/// using the template already did. Elsewhere, or without `content`, renders the block `name`,
+/// or `content` if it isn't defined. Functions are called to render them, anything else is printed.
+fn block(state: &mut State, args: Vec<Option<Value>>, writer: &mut dyn Write) -> Value {
+    let (page, name) = match page_arg(state, "block", &args) {
+        Ok(arg) => arg,
+        Err(err) => return err,
+    };
+    let content = args.get(1).cloned().flatten();
+    let defining = page.layout.borrow().is_some();
+    if let (true, Some(content)) = (defining, &content) {
+        page.blocks.borrow_mut().entry(name).or_insert_with(|| content.clone());
+        return Null;
+    }
+    let content = page.blocks.borrow().get(&name).cloned().or(content);
+    let rv = match content {
+        Some(content @ FnDecl(..)) => call(state, writer, None, content, Vec::new()),
+        Some(content) => {
+            let print = state.get(&String::from("print")).cloned().unwrap_or(Null);
+            call(state, writer, Some("print"), print, vec![Some(content)])
+        },
+        None => Null,
+    };
+    match rv {
+        Error(err) => Error(err),
+        _ => Null,
+    }
+}
+
+/// Resource limits for a single evaluation. `None` means unbounded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub max_time: Option<Duration>,
+    pub max_depth: Option<usize>,
+    pub max_size: Option<usize>,
+}
+
+impl Limits {
+    pub fn unlimited() -> Limits {
+        Limits{ max_steps: None, max_time: None, max_depth: None, max_size: None }
+    }
+}
+
+/// Deepest nesting of arrays and hashes a value may have. Values are printed, encoded, compared,
+/// copied and dropped by recursing into them, which deeper ones could exhaust the stack with.
+pub const MAX_NESTING: usize = 256;
+
+/// Stack size of the threads programs are evaluated on, which recursion that isn't in tail position uses up.
+pub const STACK_SIZE: usize = 100 * 1024 * 1024;
+
+/// Stack size of threads that weren't set up to evaluate programs, that of threads spawned by Rust.
+/// Main threads usually get more.
+const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+/// Stack kept free for what runs between two checks of how much is used, including builtins.
+const STACK_MARGIN: usize = 256 * 1024;
+
+thread_local! {
+    static THREAD_STACK_SIZE: Cell<usize> = const { Cell::new(DEFAULT_STACK_SIZE) };
+}
+
+/// Tells evaluation on the current thread how large the thread's stack is, so that running out of
+/// it is an error rather than an abort.
+pub fn set_stack_size(size: usize) {
+    THREAD_STACK_SIZE.with(|stack_size| stack_size.set(size));
+}
+
+/// Runs `f` on a thread with a stack of `STACK_SIZE`, for evaluating programs that recurse deeply.
+pub fn with_stack<T: Send, F: FnOnce() -> T + Send>(f: F) -> T {
+    thread::scope(|scope| {
+        let thread = thread::Builder::new().stack_size(STACK_SIZE).spawn_scoped(scope, || {
+            set_stack_size(STACK_SIZE);
+            f()
+        }).expect("Could not start a thread to evaluate on");
+        thread.join().unwrap_or_else(|panic| ::std::panic::resume_unwind(panic))
+    })
+}
+
+/// Usage counters shared between a `State` and all the function scopes cloned from it.
+struct Budget {
+    limits: Limits,
+    started: Instant,
+    steps: Cell<u64>,
+    depth: Cell<usize>,
+    /// Address of the outermost evaluation on the stack seen so far, which it grows down from
+    stack_base: Cell<usize>,
+    /// How much of the stack evaluation may use
+    stack_limit: usize,
+}
+
+impl Budget {
+    fn new(limits: Limits) -> Budget {
+        let stack_size = THREAD_STACK_SIZE.with(Cell::get);
+        let stack_limit = stack_size - STACK_MARGIN.min(stack_size / 2);
+        Budget{ limits, started: Instant::now(), steps: Cell::new(0), depth: Cell::new(0), stack_base: Cell::new(0), stack_limit }
+    }
+
+    fn tick(&self) -> Option<RuntimeError> {
+        let marker = 0u8;
+        let here = &marker as *const u8 as usize;
+        if here > self.stack_base.get() {
+            self.stack_base.set(here);
+        } else if self.stack_base.get() - here > self.stack_limit {
+            return Some(RuntimeError::new(ErrorKind::StackOverflow, "stack exhausted by nested calls"));
+        }
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if self.limits.max_steps.is_some_and(|max| steps > max) {
+            return Some(RuntimeError::new(ErrorKind::LimitExceeded, "evaluation step limit exceeded"));
+        }
+        // Checking the clock is comparatively expensive, so only do it every so often
+        if steps.is_multiple_of(1024) && self.limits.max_time.is_some_and(|max| self.started.elapsed() > max) {
+            return Some(RuntimeError::new(ErrorKind::LimitExceeded, "evaluation time limit exceeded"));
+        }
+        None
+    }
+
+    fn enter(&self) -> Option<RuntimeError> {
+        let depth = self.depth.get() + 1;
+        if self.limits.max_depth.is_some_and(|max| depth > max) {
+            return Some(RuntimeError::new(ErrorKind::LimitExceeded, "recursion depth limit exceeded"));
+        }
+        self.depth.set(depth);
+        None
+    }
+
+    fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    /// Checks a value that has just been built against the size limit and `MAX_NESTING`, which
+    /// applies whatever the limits.
+    fn check_size(&self, val: Value) -> Value {
+        match val.measure(self.limits.max_size.unwrap_or(usize::MAX)) {
+            Ok(_) => val,
+            Err(message) => Error(RuntimeError::new(ErrorKind::LimitExceeded, message)),
+        }
+    }
+
+    /// Checks the array that joining `a1` and `a2` would make, before it is built, so an array
+    /// grown in a loop is neither copied nor walked past the size limit.
+    fn check_concat(&self, a1: &[Value], a2: &[Value]) -> Option<RuntimeError> {
+        let elements = a1.iter().chain(a2).map(|v| (v, 1)).collect();
+        let max = self.limits.max_size.unwrap_or(usize::MAX);
+        measure(elements, a1.len() + a2.len(), max).err()
+            .map(|message| RuntimeError::new(ErrorKind::LimitExceeded, message))
+    }
+
+    /// Checks the length of a string about to be built, which is all its size amounts to.
+    fn check_len(&self, len: usize) -> Option<RuntimeError> {
+        match self.limits.max_size {
+            Some(max) if len > max => Some(RuntimeError::new(ErrorKind::LimitExceeded, "value size limit exceeded")),
+            _ => None,
+        }
+    }
+}
+
+/// Loads the templates used by `include` and `layout`, given their path as written in the page.
+pub trait TemplateLoader {
+    fn load(&self, path: &str) -> ::std::result::Result<Arc<Program>, RuntimeError>;
+}
+
+/// What a page being rendered shares with the templates it includes and its layouts.
+struct Page {
+    loader: Rc<dyn TemplateLoader>,
+    /// Layout declared by the template being evaluated, rendered once it is done
+    layout: RefCell<Option<String>>,
+    blocks: RefCell<HashMap<String, Value>>,
+}
+
 #[derive(Clone)]
 pub struct State {
     state: HashMap<String, Value>,
+    budget: Rc<Budget>,
+    page: Option<Rc<Page>>,
+}
+
+/// Names of the builtins and prelude functions, with the fewest and most arguments they make use
+/// of. `None` means any number.
+pub const BUILTINS: &[(&str, usize, Option<usize>)] = &[
+    ("len", 1, Some(1)),
+    ("print", 0, None),
+    ("println", 0, None),
+    ("insert", 3, Some(3)),
+    ("keys", 1, Some(1)),
+    ("raw", 1, Some(1)),
+    ("escape_html", 1, Some(1)),
+    ("escape_attr", 1, Some(1)),
+    ("escape_url", 1, Some(1)),
+    ("json_encode", 1, Some(1)),
+    ("json_decode", 1, Some(1)),
+    ("include", 1, Some(2)),
+    ("layout", 1, Some(1)),
+    ("block", 1, Some(2)),
+    ("first", 1, Some(1)),
+    ("last", 1, Some(1)),
+    ("tail", 1, Some(1)),
+    ("push", 2, Some(2)),
+    ("map", 2, Some(2)),
+];
+
+/// The fewest and most arguments a function with the given parameters takes, `None` meaning any number.
+pub fn arity(params: &[Param]) -> (usize, Option<usize>) {
+    let required = params.iter().filter(|param| param.default.is_none() && !param.rest).count();
+    let rest = params.iter().any(|param| param.rest);
+    (required, if rest { None } else { Some(params.len()) })
+}
+
+/// Describes how many arguments a function takes, e.g. `1 to 2 arguments`.
+pub fn arguments(min: usize, max: Option<usize>) -> String {
+    let plural = |n| if n == 1 { "1 argument".to_string() } else { format!("{} arguments", n) };
+    match max {
+        Some(max) if max == min => plural(min),
+        Some(max) => format!("{} to {} arguments", min, max),
+        None if min == 0 => String::from("any number of arguments"),
+        None => format!("at least {}", plural(min)),
+    }
+}
+
+/// Explains why a call to `name` with `given` arguments is wrong, if the function doesn't take that many.
+pub fn arity_mismatch(name: &str, (min, max): (usize, Option<usize>), given: usize) -> Option<String> {
+    if given >= min && max.is_none_or(|max| given <= max) {
+        return None;
+    }
+    let given = if given == 1 { String::from("1 was") } else { format!("{} were", given) };
+    Some(format!("{} takes {} but {} given", name, arguments(min, max), given))
+}
+
+lazy_static! {
+    /// Bindings of the builtins and prelude functions, evaluated once and copied into every new `State`.
+    static ref PRELUDE: HashMap<String, Value> = State::prelude().state;
 }
 
 impl State {
     pub fn new() -> State {
+        State{ state: PRELUDE.clone(), budget: Rc::new(Budget::new(Limits::unlimited())), page: None }
+    }
+
+    fn prelude() -> State {
         let mut state = HashMap::new();
         state.insert(String::from("len"), Value::FnBuiltin(String::from("len"), Box::new(|v| {
             (Box::new(match v.get(0) {
@@ -110,7 +585,33 @@ impl State {
             };
             (Box::new(keys.unwrap_or(Null)), None)
         })));
-        let mut state = State{ state };
+        state.insert(String::from("raw"), Value::FnBuiltin(String::from("raw"), Box::new(|v| {
+            (Box::new(Raw(v.first().cloned().flatten().unwrap_or(Null).to_string())), None)
+        })));
+        state.insert(String::from("escape_html"), Value::FnBuiltin(String::from("escape_html"), Box::new(|v| {
+            (Box::new(Str(escape::html(&v.first().cloned().flatten().unwrap_or(Null).to_string()))), None)
+        })));
+        state.insert(String::from("escape_attr"), Value::FnBuiltin(String::from("escape_attr"), Box::new(|v| {
+            (Box::new(Str(escape::attr(&v.first().cloned().flatten().unwrap_or(Null).to_string()))), None)
+        })));
+        state.insert(String::from("escape_url"), Value::FnBuiltin(String::from("escape_url"), Box::new(|v| {
+            (Box::new(Str(escape::url(&v.first().cloned().flatten().unwrap_or(Null).to_string()))), None)
+        })));
+        state.insert(String::from("json_encode"), Value::FnBuiltin(String::from("json_encode"), Box::new(|v| {
+            let val = v.first().cloned().flatten().unwrap_or(Null);
+            (Box::new(json::encode(&val).map(Str).unwrap_or_else(|e| Error(RuntimeError::new(ErrorKind::Json, &e)))), None)
+        })));
+        state.insert(String::from("json_decode"), Value::FnBuiltin(String::from("json_decode"), Box::new(|v| {
+            let val = match v.first() {
+                Some(Some(Str(s))) | Some(Some(Raw(s))) => json::decode(s).unwrap_or_else(|e| Error(RuntimeError::new(ErrorKind::Json, &e))),
+                _ => Error(RuntimeError::new(ErrorKind::Json, "json_decode expects a string")),
+            };
+            (Box::new(val), None)
+        })));
+        state.insert(String::from("include"), Value::FnState(String::from("include"), Box::new(include)));
+        state.insert(String::from("layout"), Value::FnState(String::from("layout"), Box::new(layout)));
+        state.insert(String::from("block"), Value::FnState(String::from("block"), Box::new(block)));
+        let mut state = State{ state, budget: Rc::new(Budget::new(Limits::unlimited())), page: None };
         let mut out: Vec<u8> = Vec::new();
         state.eval("let first = fn(a) a[0]", &mut out);
         state.eval("let last = fn(a) a[len(a)-1]", &mut out);
@@ -120,19 +621,93 @@ impl State {
         state
     }
 
+    /// Makes `print` and `println` HTML-escape everything they are given, except values wrapped with `raw`.
+    pub fn escape_output(&mut self) {
+        self.state.insert(String::from("print"), Value::FnBuiltin(String::from("print"), Box::new(|v| {
+            let out = v.iter().flatten().map(escaped).collect::<String>();
+            (Box::new(Null), Some(out))
+        })));
+        self.state.insert(String::from("println"), Value::FnBuiltin(String::from("println"), Box::new(|v| {
+            let out = v.iter().flatten().map(|val| escaped(val) + "\n").collect::<String>();
+            (Box::new(Null), Some(out))
+        })));
+    }
+
+    /// Makes `include`, `layout` and `block` usable, with templates loaded by `loader`.
+    pub fn set_loader(&mut self, loader: Rc<dyn TemplateLoader>) {
+        self.page = Some(Rc::new(Page{ loader, layout: RefCell::new(None), blocks: RefCell::new(HashMap::new()) }));
+    }
+
+    /// Evaluates a page. If it declares a layout, the layout is rendered instead, with the output
+    /// of the page as its `content` block; a page that only defines blocks may define `content` itself.
+    pub fn render(&mut self, program: &Program, writer: &mut dyn Write) -> Option<Value> {
+        let mut body = Vec::new();
+        let rv = try_eval!(program.eval(self, &mut body));
+        let page = match self.page {
+            Some(ref page) => Rc::clone(page),
+            None => {
+                let _ = writer.write_all(&body);
+                return rv;
+            },
+        };
+        let path = page.layout.borrow_mut().take();
+        let path = match path {
+            Some(path) => path,
+            None => {
+                let _ = writer.write_all(&body);
+                return rv;
+            },
+        };
+        let layout = match page.loader.load(&path) {
+            Ok(layout) => layout,
+            Err(err) => return Some(Error(err)),
+        };
+        let body = String::from_utf8_lossy(&body).into_owned();
+        if !body.trim().is_empty() || !page.blocks.borrow().contains_key("content") {
+            page.blocks.borrow_mut().insert(String::from("content"), Raw(body));
+        }
+        if let Some(err) = self.budget.enter() {
+            return Some(Error(err));
+        }
+        let rv = self.render(&layout, writer);
+        self.budget.leave();
+        rv
+    }
+
+    /// Calls the function bound to `name`, returning `None` if there is no such binding.
+    pub fn call(&mut self, name: &str, args: Vec<Value>, writer: &mut dyn Write) -> Option<Value> {
+        let func = self.get(&String::from(name))?.clone();
+        Some(call(self, writer, Some(name), func, args.into_iter().map(Some).collect()))
+    }
+
     pub fn set(&mut self, name: &String, value: Value) {
         self.state.insert(name.clone(), value);
     }
 
+    /// Bindings made since the state was created, sorted by name. Builtins and prelude functions
+    /// are left out unless they were rebound.
+    pub fn bindings(&self) -> Vec<(&String, &Value)> {
+        let mut bindings = self.state.iter().filter(|(name, value)| PRELUDE.get(*name) != Some(*value)).collect::<Vec<_>>();
+        bindings.sort_by_key(|(name, _)| *name);
+        bindings
+    }
+
     pub fn get(&self, name: &String) -> Option<&Value> {
         self.state.get(name)
     }
 
+    /// Applies `limits` to everything evaluated from now on, resetting the usage counters.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.budget = Rc::new(Budget::new(limits));
+    }
+
     pub fn eval(&mut self, input: &str, writer: &mut Write) -> Option<Value> {
         let mut lexer = Lexer::new(String::from(input));
         let mut parser = Parser::new(&mut lexer);
-        let program = parser.parse_program();
-        program.eval(self, writer)
+        match parser.parse_program() {
+            Ok(program) => program.eval(self, writer),
+            Err(err) => Some(Error(RuntimeError::new(ErrorKind::Syntax, &err.to_string()))),
+        }
     }
 }
 
@@ -144,7 +719,7 @@ impl Eval for Program {
     fn eval(&self, state: &mut State, writer: &mut Write) -> Option<Value> {
         let mut rv = None;
         for st in self.statements() {
-            rv = st.eval(state, writer);
+            rv = try_eval!(st.eval(state, writer));
         }
         rv
     }
@@ -154,15 +729,15 @@ impl Eval for Statement {
     fn eval(&self, state: &mut State, writer: &mut Write) -> Option<Value> {
         match self {
             Let(s, exp) => {
-                let val = exp.eval(state, writer).unwrap().unret();
+                let val = try_eval!(exp.eval(state, writer)).unwrap().unret();
                 state.set(&s, val);
                 None
             },
-            Ret(exp) => exp.eval(state, writer).map(|v| RetVal(Box::new(v.unret()))),
+            Ret(exp) => try_eval!(exp.eval(state, writer)).map(|v| RetVal(Box::new(v.unret()))),
             BlockStatement(stmts) => {
                 let mut val = None;
                 for st in stmts {
-                    val = st.eval(state, writer);
+                    val = try_eval!(st.eval(state, writer));
                     if let Some(RetVal(_)) = val {
                         break;
                     }
@@ -170,17 +745,63 @@ impl Eval for Statement {
                 val
             },
             ExprStatement(exp) => exp.eval(state, writer).map(|v| v),
+            Throw(exp) => {
+                let val = try_eval!(exp.eval(state, writer)).unwrap_or(Null).unret();
+                Some(Error(RuntimeError::thrown(val)))
+            },
+            Try(body, handler, finally) => {
+                let mut rv = body.eval(state, writer);
+                if let Some((name, handler)) = handler {
+                    if let Some(Error(err)) = rv.clone() {
+                        if err.catchable() {
+                            // What was thrown is nested in what is caught
+                            match state.budget.check_size(err.caught()) {
+                                Error(err) => rv = Some(Error(err)),
+                                caught => {
+                                    state.set(name, caught);
+                                    rv = handler.eval(state, writer);
+                                },
+                            }
+                        }
+                    }
+                }
+                match (&rv, finally) {
+                    (Some(Error(err)), _) if !err.catchable() => {},
+                    // Leaving the finally block early takes the place of what the try block came to
+                    (_, Some(finally)) => match finally.eval(state, writer) {
+                        Some(Error(err)) => return Some(Error(err)),
+                        Some(RetVal(v)) => return Some(RetVal(v)),
+                        _ => {},
+                    },
+                    _ => {},
+                }
+                rv
+            },
+            // Written as it is, whatever the page has bound `print` and `raw` to
+            Text(text) => {
+                let _ = writer.write_all(text.as_bytes());
+                Some(Null)
+            },
         }
     }
 }
 
-fn math_op(l: Option<Value>, r: Option<Value>, op: &Fn(i32, i32) -> i32) -> Value {
+fn math_op(l: Option<Value>, r: Option<Value>, op: &Fn(i32, i32) -> Option<i32>) -> Value {
     match (l, r) {
-        (Some(Int(lv)), Some(Int(rv))) => Int(op(lv, rv)),
+        (Some(Int(lv)), Some(Int(rv))) => match op(lv, rv) {
+            Some(result) => Int(result),
+            // Only division fails with a right operand of zero
+            None if rv == 0 => arithmetic_error("division by zero"),
+            None => arithmetic_error("integer overflow"),
+        },
         _ => Null,
     }
 }
 
+fn arithmetic_error(message: &str) -> Value {
+    Error(RuntimeError::new(ErrorKind::Arithmetic, message))
+}
+
 fn bool_op(l: Option<Value>, r: Option<Value>, op: &Fn(i32, i32) -> bool) -> Value {
     match (l, r) {
         (Some(Int(lv)), Some(Int(rv))) => Bool(op(lv, rv)),
@@ -193,6 +814,7 @@ fn test_eq(l: Option<Value>, r: Option<Value>) -> bool {
         (Some(Int(lv)), Some(Int(rv))) => lv == rv,
         (Some(Bool(lv)), Some(Bool(rv))) => lv == rv,
         (Some(Str(lv)), Some(Str(rv))) => lv == rv,
+        (Some(Raw(lv)), Some(Raw(rv))) => lv == rv,
         (Some(Array(lv)), Some(Array(rv))) => lv == rv,
         (Some(Hash(lv)), Some(Hash(rv))) => lv == rv,
         (Some(Null), Some(Null)) => true,
@@ -202,6 +824,9 @@ fn test_eq(l: Option<Value>, r: Option<Value>) -> bool {
 
 impl Eval for Expression {
     fn eval(&self, state: &mut State, writer: &mut Write) -> Option<Value> {
+        if let Some(err) = state.budget.tick() {
+            return Some(Error(err));
+        }
         Some(match self {
             Expression::Int(i) => Int(*i),
             Expression::True => Bool(true),
@@ -209,40 +834,59 @@ impl Eval for Expression {
             Expression::Null => Null,
             Expression::Hash(v) => {
                 let mut h = HashMap::new();
-                v.iter().for_each(|(k, v)| {
-                    k.eval(state, writer).map(|k|
-                        v.eval(state, writer).map(|v| h.insert(k.to_string(), v)));
-                });
-                Hash(h)
+                for (k, v) in v {
+                    if let Some(k) = try_eval!(k.eval(state, writer)) {
+                        if let Some(v) = try_eval!(v.eval(state, writer)) {
+                            h.insert(k.to_string(), v);
+                        }
+                    }
+                }
+                state.budget.check_size(Hash(h))
             },
             Expression::Plus(l, r) => {
-                let lev = l.eval(state, writer);
-                let rev = r.eval(state, writer);
+                let lev = try_eval!(l.eval(state, writer));
+                let rev = try_eval!(r.eval(state, writer));
                 match (lev, rev) {
-                    (Some(Array(a1)), Some(Array(a2))) => {
-                        let mut all = Vec::new();
-                        all.extend(a1.clone());
-                        all.extend(a2.clone());
-                        Array(all)
+                    (Some(Array(mut a1)), Some(Array(a2))) => match state.budget.check_concat(&a1, &a2) {
+                        Some(err) => Error(err),
+                        None => {
+                            a1.extend(a2);
+                            Array(a1)
+                        },
+                    },
+                    (Some(Raw(mut s1)), Some(Raw(s2))) => match state.budget.check_len(s1.len() + s2.len()) {
+                        Some(err) => Error(err),
+                        None => {
+                            s1.push_str(&s2);
+                            Raw(s1)
+                        },
                     },
-                    (Some(Str(s)), rev) => Str(format!("{}{}", s, rev.unwrap_or(Null))),
-                    (lev, Some(Str(s))) => Str(format!("{}{}", lev.unwrap_or(Null), s)),
-                    (lev, rev) => math_op(lev, rev, &|l, r| l + r),
+                    (lev @ Some(Str(_)), rev) | (lev, rev @ Some(Str(_))) => {
+                        let (l, r) = (lev.unwrap_or(Null).to_string(), rev.unwrap_or(Null).to_string());
+                        match state.budget.check_len(l.len() + r.len()) {
+                            Some(err) => Error(err),
+                            None => Str(l + &r),
+                        }
+                    },
+                    (lev, rev) => math_op(lev, rev, &i32::checked_add),
                 }
             },
-            Expression::Minus(l, r) => math_op(l.eval(state, writer), r.eval(state, writer), &|l, r| l - r),
-            Expression::Div(l, r) => math_op(l.eval(state, writer), r.eval(state, writer), &|l, r| l / r),
-            Expression::Mul(l, r) => math_op(l.eval(state, writer), r.eval(state, writer), &|l, r| l * r),
-            Expression::Eq(l, r) => Bool(test_eq(l.eval(state, writer), r.eval(state, writer))),
-            Expression::Ne(l, r) => Bool(!test_eq(l.eval(state, writer), r.eval(state, writer))),
-            Expression::Lt(l, r) => bool_op(l.eval(state, writer), r.eval(state, writer), &|l, r| l < r),
-            Expression::Gt(l, r) => bool_op(l.eval(state, writer), r.eval(state, writer), &|l, r| l > r),
+            Expression::Minus(l, r) => math_op(try_eval!(l.eval(state, writer)), try_eval!(r.eval(state, writer)), &i32::checked_sub),
+            Expression::Div(l, r) => math_op(try_eval!(l.eval(state, writer)), try_eval!(r.eval(state, writer)), &i32::checked_div),
+            Expression::Mul(l, r) => math_op(try_eval!(l.eval(state, writer)), try_eval!(r.eval(state, writer)), &i32::checked_mul),
+            Expression::Eq(l, r) => Bool(test_eq(try_eval!(l.eval(state, writer)), try_eval!(r.eval(state, writer)))),
+            Expression::Ne(l, r) => Bool(!test_eq(try_eval!(l.eval(state, writer)), try_eval!(r.eval(state, writer)))),
+            Expression::Lt(l, r) => bool_op(try_eval!(l.eval(state, writer)), try_eval!(r.eval(state, writer)), &|l, r| l < r),
+            Expression::Gt(l, r) => bool_op(try_eval!(l.eval(state, writer)), try_eval!(r.eval(state, writer)), &|l, r| l > r),
             Expression::Ident(id) => state.get(&id).unwrap_or(&Null).clone(),
             Expression::String(s) => Value::Str(s.clone()),
-            Expression::Neg(n) => if let Some(Int(i)) = n.eval(state, writer) { Int(-i) } else { Null },
-            Expression::Not(n) => if let Some(Bool(b)) = n.eval(state, writer) { Bool(!b) } else { Null },
+            Expression::Neg(n) => match try_eval!(n.eval(state, writer)) {
+                Some(Int(i)) => i.checked_neg().map_or_else(|| arithmetic_error("integer overflow"), Int),
+                _ => Null,
+            },
+            Expression::Not(n) => if let Some(Bool(b)) = try_eval!(n.eval(state, writer)) { Bool(!b) } else { Null },
             Expression::If(cond, ifb, elb) => {
-                let cond_val = cond.eval(state, writer);
+                let cond_val = try_eval!(cond.eval(state, writer));
                 match cond_val {
                     Some(Bool(true)) => ifb.eval(state, writer).unwrap_or(Null),
                     Some(Bool(false)) => elb.eval(state, writer).unwrap_or(Null),
@@ -250,35 +894,227 @@ impl Eval for Expression {
                 }
             },
             Expression::FnDecl(pars, stmt) => FnDecl(pars.clone(), stmt.clone()),
-            Expression::Array(elems) => Array(elems.iter().map(|el| el.eval(state, writer).unwrap_or(Null)).collect::<Vec<Value>>()),
+            Expression::Array(elems) => {
+                let mut arr = Vec::new();
+                for el in elems {
+                    arr.push(try_eval!(el.eval(state, writer)).unwrap_or(Null));
+                }
+                state.budget.check_size(Array(arr))
+            },
             Expression::Index(arr, index) => {
-                match (arr.eval(state, writer), index.eval(state, writer)) {
+                match (try_eval!(arr.eval(state, writer)), try_eval!(index.eval(state, writer))) {
                     (Some(Array(a)), Some(Int(i))) => a.get(i as usize).unwrap_or(&Null).clone(),
                     (Some(Hash(hash)), Some(val)) => hash.get(&val.to_string()).unwrap_or(&Null).clone(),
                     _ => Null,
                 }
             },
-            Expression::Call(func, actual) => {
-                match func.eval(state, writer) {
-                    Some(FnDecl(formal, stmt)) => {
-                        let mut fn_state = state.clone();
-                        for i in 0..cmp::min(actual.len(), formal.len()) {
-                            fn_state.set(&formal[i], actual[i].eval(state, writer).unwrap_or(Null));
-                        }
-                        stmt.eval(&mut fn_state, writer).unwrap_or(Null).unret()
-                    },
-                    Some(FnBuiltin(_, builtin)) => {
-                        let (val, out) = builtin(actual.iter().map(|a| a.eval(state, writer)).collect::<Vec<Option<Value>>>());
-                        out.map(|out| writer.write(out.as_bytes()));
-                        *val
-                    },
-                    _ => Null,
-                }
+            Expression::Call(func, actual) => match evaluate_call(func, actual, state, writer) {
+                Ok((name, func, args)) => call(state, writer, name.as_deref(), func, args),
+                Err(err) => err,
             },
+            Expression::Spread(_) => type_error("... can only be used on the arguments of a call"),
         })
     }
 }
 
+fn type_error(message: &str) -> Value {
+    Error(RuntimeError::new(ErrorKind::Type, message))
+}
+
+/// Calls `func` with arguments that have already been evaluated. `name` is what the function was
+/// called by, if anything, for errors to refer to it.
+fn call(state: &mut State, writer: &mut dyn Write, name: Option<&str>, func: Value, args: Vec<Option<Value>>) -> Value {
+    if let Some(err) = check_arity(name, &func, args.len()) {
+        return err;
+    }
+    match func {
+        FnDecl(..) => {
+            if let Some(err) = state.budget.enter() {
+                return Error(err);
+            }
+            let rv = call_function(state.clone(), writer, name.map(String::from), func, args);
+            state.budget.leave();
+            rv
+        },
+        FnBuiltin(_, builtin) => {
+            let (val, out) = builtin(args);
+            if let Some(out) = out {
+                let _ = writer.write_all(out.as_bytes());
+            }
+            state.budget.check_size(*val)
+        },
+        FnState(_, builtin) => {
+            let val = builtin(state, args, writer);
+            state.budget.check_size(val)
+        },
+        _ => Null,
+    }
+}
+
+fn check_arity(name: Option<&str>, func: &Value, given: usize) -> Option<Value> {
+    let arity = match func {
+        FnDecl(formal, _) => arity(formal),
+        FnBuiltin(builtin, _) | FnState(builtin, _) => BUILTINS.iter()
+            .find(|(name, _, _)| name == builtin)
+            .map(|&(_, min, max)| (min, max))?,
+        _ => return None,
+    };
+    let message = arity_mismatch(&name.map_or_else(|| func.to_string(), String::from), arity, given)?;
+    Some(Error(RuntimeError::new(ErrorKind::Arity, &message)))
+}
+
+/// Calls a function of the program in `scope`, a copy of the caller's. Calls the function returns
+/// the result of are made in turn rather than nested, so that recursion in tail position doesn't
+/// use up the native stack. The scope is handed on to them, as the function has no more use for it.
+fn call_function(mut scope: State, writer: &mut dyn Write, mut name: Option<String>, mut func: Value, mut args: Vec<Option<Value>>) -> Value {
+    loop {
+        let (formal, body) = match func {
+            FnDecl(formal, body) => (formal, body),
+            other => return call(&mut scope, writer, name.as_deref(), other, args),
+        };
+        let mut given = args.into_iter();
+        for param in &formal {
+            let value = match (given.next(), &param.default) {
+                (first, _) if param.rest => match scope.budget.check_size(Array(first.into_iter().chain(given.by_ref()).map(|arg| arg.unwrap_or(Null)).collect())) {
+                    Error(err) => return traced(err, name, &formal),
+                    rest => rest,
+                },
+                (Some(arg), _) => arg.unwrap_or(Null),
+                // Defaults are evaluated in the scope of the call, so they can refer to the parameters before them
+                (None, Some(default)) => match default.eval(&mut scope, writer) {
+                    Some(Error(err)) => return traced(err, name, &formal),
+                    val => val.unwrap_or(Null).unret(),
+                },
+                (None, None) => Null,
+            };
+            scope.set(&param.name, value);
+        }
+        match tail_statement(&body, true, &mut scope, writer) {
+            Tail::Done(Some(Error(err))) => return traced(err, name, &formal),
+            Tail::Done(rv) => return rv.unwrap_or(Null).unret(),
+            Tail::Call(next_name, next_func, next_args) => {
+                if let Some(Error(err)) = check_arity(next_name.as_deref(), &next_func, next_args.len()) {
+                    return traced(err, name, &formal);
+                }
+                name = next_name;
+                func = next_func;
+                args = next_args;
+            },
+        }
+    }
+}
+
+/// Adds the function an error leaves to its trace, by the name it was called by if any. Calls in
+/// tail position take the place of their caller, which is left out.
+fn traced(mut err: RuntimeError, name: Option<String>, formal: &[Param]) -> Value {
+    if err.trace.len() < MAX_TRACE {
+        err.trace.push(name.unwrap_or_else(|| format!("fn({})", format_params(formal))));
+    } else {
+        err.omitted += 1;
+    }
+    Error(err)
+}
+
+/// What a statement of a function body comes to.
+enum Tail {
+    Done(Option<Value>),
+    /// A call the function returns the result of, with what it was called by, left for `call_function` to make
+    Call(Option<String>, Value, Vec<Option<Value>>),
+}
+
+/// Evaluates a statement of a function body as `Statement::eval` does, except for calls in tail
+/// position: those made by `return`, and the last one evaluated if `tail` is set, the value of
+/// the statement being that of the function.
+fn tail_statement(statement: &Statement, tail: bool, state: &mut State, writer: &mut dyn Write) -> Tail {
+    match statement {
+        Ret(e) => match tail_expression(e, true, state, writer) {
+            Tail::Done(Some(Error(err))) => Tail::Done(Some(Error(err))),
+            Tail::Done(val) => Tail::Done(val.map(|v| RetVal(Box::new(v.unret())))),
+            call => call,
+        },
+        BlockStatement(stmts) => {
+            let mut val = None;
+            for (i, st) in stmts.iter().enumerate() {
+                match tail_statement(st, tail && i + 1 == stmts.len(), state, writer) {
+                    Tail::Done(Some(Error(err))) => return Tail::Done(Some(Error(err))),
+                    Tail::Done(Some(RetVal(v))) => return Tail::Done(Some(RetVal(v))),
+                    Tail::Done(v) => val = v,
+                    call => return call,
+                }
+            }
+            Tail::Done(val)
+        },
+        ExprStatement(e) => tail_expression(e, tail, state, writer),
+        // Calls in a try block have to return for their errors to be caught
+        Let(..) | Throw(_) | Try(..) | Text(_) => Tail::Done(statement.eval(state, writer)),
+    }
+}
+
+/// Evaluates an expression of a function body, see `tail_statement`. A `return` in the branches
+/// of an `if` is in tail position even when the `if` isn't.
+fn tail_expression(e: &Expression, tail: bool, state: &mut State, writer: &mut dyn Write) -> Tail {
+    let done = |val: Value| Tail::Done(Some(val));
+    match e {
+        Expression::If(cond, ifb, elb) => {
+            if let Some(err) = state.budget.tick() {
+                return done(Error(err));
+            }
+            let branch = match cond.eval(state, writer) {
+                Some(Error(err)) => return done(Error(err)),
+                Some(Bool(true)) => ifb,
+                Some(Bool(false)) => elb,
+                _ => return done(Null),
+            };
+            match tail_statement(branch, tail, state, writer) {
+                Tail::Done(val) => done(val.unwrap_or(Null)),
+                call => call,
+            }
+        },
+        Expression::Call(func, actual) if tail => {
+            if let Some(err) = state.budget.tick() {
+                return done(Error(err));
+            }
+            match evaluate_call(func, actual, state, writer) {
+                Ok((name, func, args)) => Tail::Call(name, func, args),
+                Err(err) => done(err),
+            }
+        },
+        _ => Tail::Done(e.eval(state, writer)),
+    }
+}
+
+/// What a function was called by, the function and its arguments.
+type PreparedCall = (Option<String>, Value, Vec<Option<Value>>);
+
+/// Evaluates the function and arguments of a call, spreading arrays where asked to. Returns the
+/// error if one of them fails.
+fn evaluate_call(func: &Expression, actual: &[Expression], state: &mut State, writer: &mut dyn Write)
+    -> ::std::result::Result<PreparedCall, Value> {
+    let name = match func {
+        Expression::Ident(name) => Some(name.clone()),
+        _ => None,
+    };
+    let func = match func.eval(state, writer) {
+        Some(Error(err)) => return Err(Error(err)),
+        func => func.unwrap_or(Null),
+    };
+    let mut args = Vec::new();
+    for a in actual {
+        match a {
+            Expression::Spread(array) => match array.eval(state, writer) {
+                Some(Array(elems)) => args.extend(elems.into_iter().map(Some)),
+                Some(Error(err)) => return Err(Error(err)),
+                other => return Err(type_error(&format!("only arrays can be spread, not {}", other.unwrap_or(Null).type_name()))),
+            },
+            _ => match a.eval(state, writer) {
+                Some(Error(err)) => return Err(Error(err)),
+                arg => args.push(arg),
+            },
+        }
+    }
+    Ok((name, func, args))
+}
+
 pub fn eval(input: &str) -> Option<Value> {
     let mut out = io::stdout();
     State::new().eval(input, &mut out).map(|v| v.clone())
@@ -288,7 +1124,24 @@ pub fn eval(input: &str) -> Option<Value> {
 mod test {
     use super::eval;
     use super::Value::*;
-    use super::State;
+    use super::{ State, Value, Limits, ErrorKind, BUILTINS, MAX_TRACE, arity };
+    use std::time::Duration;
+
+    fn eval_limited(input: &str, limits: Limits) -> Option<Value> {
+        let mut state = State::new();
+        state.set_limits(limits);
+        state.eval(input, &mut Vec::new())
+    }
+
+    fn assert_limit_exceeded(val: Option<Value>, message: &str) {
+        match val {
+            Some(Error(err)) => {
+                assert_eq!(err.kind, ErrorKind::LimitExceeded);
+                assert_eq!(err.message, message);
+            },
+            other => panic!("expected a limit error, got {:?}", other),
+        }
+    }
 
     #[test]
     fn test_prims() {
@@ -349,6 +1202,30 @@ mod test {
         assert_eq!(eval("map([1,2,3,4], fn(x) x*2+1)").unwrap(), Array(vec![Int(3), Int(5), Int(7), Int(9)]));
     }
 
+    #[test]
+    fn test_arity() {
+        let error = |code| match eval(code).unwrap() {
+            Error(err) => (err.kind, err.message),
+            other => panic!("expected an error, got {}", other),
+        };
+        assert_eq!(error("let add = fn(a, b) a + b; add(1)"), (ErrorKind::Arity, String::from("add takes 2 arguments but 1 was given")));
+        assert_eq!(error("(fn(a, b = 1) a)(1, 2, 3)"), (ErrorKind::Arity, String::from("fn(a, b = 1) takes 1 to 2 arguments but 3 were given")));
+        assert_eq!(error("len([], [])"), (ErrorKind::Arity, String::from("len takes 1 argument but 2 were given")));
+        assert_eq!(error("let f = fn(a, ...b) a; f()"), (ErrorKind::Arity, String::from("f takes at least 1 argument but 0 were given")));
+    }
+
+    #[test]
+    fn test_params() {
+        let f = "let f = fn(a, b = a * 2, ...rest) [a, b, rest];";
+        assert_eq!(eval(&format!("{} f(1)", f)).unwrap(), Array(vec![Int(1), Int(2), Array(vec![])]));
+        assert_eq!(eval(&format!("{} f(1, 5, 6, 7)", f)).unwrap(), Array(vec![Int(1), Int(5), Array(vec![Int(6), Int(7)])]));
+        assert_eq!(eval("let add = fn(a, b) a + b; let xs = [1, 2]; add(...xs) + add(...[3], 4)").unwrap(), Int(10));
+        match eval("len(...1)").unwrap() {
+            Error(err) => assert_eq!((err.kind, err.message.as_str()), (ErrorKind::Type, "only arrays can be spread, not int")),
+            other => panic!("expected an error, got {}", other),
+        }
+    }
+
     #[test]
     fn test_hash() {
         assert_eq!(eval("let h = {\"a\": 1, true: 2}; let h2 = insert(h, true, 1); insert(h2, 0, h2)[0][true]").unwrap(), Int(1));
@@ -366,4 +1243,151 @@ mod test {
             panic!("keys should return an array");
         }
     }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(eval("json_decode(\" [1, [true, null]] \")[1][0]").unwrap(), Bool(true));
+        assert_eq!(eval("json_encode({\"a\": [1, \"b\", null]})").unwrap(), Str(String::from("{\"a\":[1,\"b\",null]}")));
+        match eval("json_encode([fn(x) x]) + 1").unwrap() {
+            Error(err) => assert_eq!(err.kind, ErrorKind::Json),
+            other => panic!("expected an error, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_builtins() {
+        let prelude = State::new().state;
+        assert_eq!(prelude.len(), BUILTINS.len());
+        for (name, min, max) in BUILTINS {
+            match prelude.get(*name) {
+                Some(FnDecl(params, _)) => assert_eq!(arity(params), (*min, *max), "{}", name),
+                Some(_) => {},
+                None => panic!("{} is not a builtin", name),
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let limits = Limits{ max_steps: Some(10000), ..Limits::unlimited() };
+        assert_limit_exceeded(eval_limited("let f = fn(x) if (x > 0) f(x-1) + f(x-1) else 1; f(20)", limits), "evaluation step limit exceeded");
+        assert_eq!(eval_limited("let f = fn(x) if (x > 0) f(x-1) + f(x-1) else 1; f(5)", limits).unwrap(), Int(32));
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let limits = Limits{ max_time: Some(Duration::from_millis(10)), ..Limits::unlimited() };
+        // Shallow enough for the stack of the test thread, and still far too many calls for the limit
+        assert_limit_exceeded(eval_limited("let f = fn(x) if (x > 0) f(x-1) + f(x-1) else 1; f(30)", limits), "evaluation time limit exceeded");
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let limits = Limits{ max_depth: Some(20), ..Limits::unlimited() };
+        assert_limit_exceeded(eval_limited("let f = fn(x) 1 + f(x+1); f(0)", limits), "recursion depth limit exceeded");
+        assert_eq!(eval_limited("let f = fn(x) if (x < 10) 1 + f(x+1) else x; f(0)", limits).unwrap(), Int(20));
+        // Calls in tail position don't nest
+        assert_eq!(eval_limited("let f = fn(x) if (x < 100) f(x+1) else x; f(0)", limits).unwrap(), Int(100));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        // Deep enough to overflow the stack of the test thread if the calls nested
+        assert_eq!(eval("let count = fn(n, acc) if (n == 0) acc else count(n - 1, acc + 1); count(100000, 0)").unwrap(), Int(100000));
+        let code = "let down = fn(n) { if (n == 0) { return \"done\" }; let m = n - 1; return down(m) }; \
+            let even = fn(n) { if (n == 0) true else odd(n - 1) }; let odd = fn(n) { if (n == 0) false else even(n - 1) }; \
+            [down(100000), even(100001), len(...[\"abc\"])]";
+        assert_eq!(eval(code).unwrap(), Array(vec![Str(String::from("done")), Bool(false), Int(3)]));
+        // A call that isn't the last thing its function does still returns to it
+        assert_eq!(eval("let f = fn(x) { if (x > 0) { f(x - 1) }; x }; f(3)").unwrap(), Int(3));
+    }
+
+    #[test]
+    fn test_throw() {
+        let code = "let find = fn(name) { throw {\"kind\": \"NotFound\", \"message\": \"no \" + name} }; \
+            let page = fn() { let found = find(\"page\"); found }; page()";
+        match eval(code).unwrap() {
+            Error(err) => {
+                assert_eq!(err.to_string(), "NotFound: no page");
+                assert_eq!(err.trace, vec![String::from("find"), String::from("page")]);
+            },
+            other => panic!("expected an error, got {}", other),
+        }
+        match eval("(fn() { throw [1] })()").unwrap() {
+            Error(err) => assert_eq!((err.kind, err.message, err.trace), (ErrorKind::Thrown(String::from("Error")), String::from("[1]"), vec![String::from("fn()")])),
+            other => panic!("expected an error, got {}", other),
+        }
+        // Deep recursion leaves a trace of bounded length
+        let deep = "let f = fn(n) { if (n == 0) { throw \"deep\" }; let r = f(n - 1); r };";
+        match eval(&format!("{} f(55)", deep)).unwrap() {
+            Error(err) => assert_eq!((err.message.as_str(), err.trace.len(), err.omitted), ("deep", MAX_TRACE, 56 - MAX_TRACE)),
+            other => panic!("expected an error, got {}", other),
+        }
+        assert_eq!(eval(&format!("{} try {{ f(55) }} catch (e) {{ [len(e[\"trace\"]), e[\"trace\"][{}]] }}", deep, MAX_TRACE)).unwrap(),
+            Array(vec![Int(MAX_TRACE as i32 + 1), Str(format!("... {} more", 56 - MAX_TRACE))]));
+    }
+
+    #[test]
+    fn test_try() {
+        let strs = |strs: &[&str]| Array(strs.iter().map(|s| Str(String::from(*s))).collect());
+        let code = "let find = fn(name) { throw {\"kind\": \"NotFound\", \"message\": \"no \" + name, \"name\": name} }; \
+            try { find(\"page\") } catch (e) { [e[\"kind\"], e[\"message\"], e[\"name\"], e[\"trace\"]] }";
+        assert_eq!(eval(code).unwrap(), Array(vec![Str(String::from("NotFound")), Str(String::from("no page")), Str(String::from("page")), strs(&["find"])]));
+        assert_eq!(eval("try { len(1, 2) } catch (e) { e[\"kind\"] + \": \" + e[\"message\"] }").unwrap(),
+            Str(String::from("Arity: len takes 1 argument but 2 were given")));
+        assert_eq!(eval("try { throw [1] } catch (e) { [e[\"value\"], e[\"message\"], e[\"kind\"]] }").unwrap(),
+            Array(vec![Array(vec![Int(1)]), Str(String::from("[1]")), Str(String::from("Error"))]));
+        assert_eq!(eval("try { 1 } catch (e) { 2 }").unwrap(), Int(1));
+        assert_eq!(eval("try { 2147483647 * 2 } catch (e) { e[\"kind\"] + \": \" + e[\"message\"] }").unwrap(),
+            Str(String::from("Arithmetic: integer overflow")));
+
+        // Errors thrown again keep their trace
+        let code = "let fail = fn() { throw \"boom\"; 1 }; let retry = fn() { try { fail() } catch (e) { throw e }; 1 }; retry()";
+        match eval(code).unwrap() {
+            Error(err) => assert_eq!((err.to_string(), err.trace), (String::from("Error: boom"), vec![String::from("fail"), String::from("retry")])),
+            other => panic!("expected an error, got {}", other),
+        }
+
+        let mut out = Vec::new();
+        let code = "try { try { throw \"inner\" } finally { print(\"cleanup \") } } catch (e) { print(e[\"message\"]) }";
+        State::new().eval(code, &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "cleanup inner");
+        assert_eq!(eval("let f = fn() { try { return 1 } finally { return 2 }; 3 }; f()").unwrap(), Int(2));
+        assert_eq!(eval("let f = fn() { try { return 1 } finally { 2 }; 3 }; f()").unwrap(), Int(1));
+
+        // Limits can't be caught, so that scripts can't go on past them
+        let limits = Limits{ max_steps: Some(1000), ..Limits::unlimited() };
+        assert_limit_exceeded(eval_limited("let f = fn() f(); try { f() } catch (e) { 1 } finally { 2 }", limits), "evaluation step limit exceeded");
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        match eval("let f = fn(n) if (n == 0) 0 else 1 + f(n - 1); f(1000000)").unwrap() {
+            Error(err) => assert_eq!((err.kind, err.message.as_str()), (ErrorKind::StackOverflow, "stack exhausted by nested calls")),
+            other => panic!("expected an error, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        // Built without nesting calls, so only the values themselves could exhaust the stack
+        let nest = "let nest = fn(n, acc) if (n == 0) acc else nest(n - 1, [acc]);";
+        assert_limit_exceeded(eval(&format!("{} nest(100000, 0)", nest)), "value nesting limit exceeded");
+        assert_limit_exceeded(eval(&format!("{} let deep = fn(...a) a; deep(nest({}, 0))", nest, super::MAX_NESTING)), "value nesting limit exceeded");
+        let code = format!("{} let a = nest({max}, 0); [len(json_encode(a)), len(a + \"\"), a == nest({max}, 0)]", nest, max = super::MAX_NESTING);
+        assert_eq!(eval(&code).unwrap(), Array(vec![Int(2 * super::MAX_NESTING as i32 + 1), Int(2 * super::MAX_NESTING as i32 + 1), Bool(true)]));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let limits = Limits{ max_size: Some(16), ..Limits::unlimited() };
+        assert_limit_exceeded(eval_limited("let x = \"abcdef\"; let y = x + x + x; len(y)", limits), "value size limit exceeded");
+        assert_limit_exceeded(eval_limited("let x = [1, 2, 3, 4, 5, 6, 7, 8, 9]; x + x", limits), "value size limit exceeded");
+        assert_eq!(eval_limited("let x = \"abcdef\"; len(x + x)", limits).unwrap(), Int(12));
+        assert_limit_exceeded(eval_limited("let x = [[1, 2, 3, 4], [5, 6, 7, 8]]; x + [9]", limits), "value size limit exceeded");
+        assert_limit_exceeded(eval_limited("let x = [\"abcdefgh\"]; x + [\"abcdefgh\"]", limits), "value size limit exceeded");
+        assert_eq!(eval_limited("let x = [1, 2, 3]; len(x + x)", limits).unwrap(), Int(6));
+        let grow = "let grow = fn(n, acc) if (n == 0) len(acc) else grow(n - 1, acc + [n]); grow(8, [])";
+        assert_eq!(eval_limited(grow, limits).unwrap(), Int(8));
+    }
 }
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+use eval::{ Value, MAX_NESTING };
+
+/// Serializes a value as JSON. Hash keys are written in sorted order; functions and errors can't be encoded.
+pub fn encode(val: &Value) -> Result<String, String> {
+    let mut out = String::new();
+    encode_into(val, &mut out)?;
+    Ok(out)
+}
+
+fn encode_into(val: &Value, out: &mut String) -> Result<(), String> {
+    match val {
+        Value::Int(i) => out.push_str(&i.to_string()),
+        Value::Bool(b) => out.push_str(&b.to_string()),
+        Value::Str(s) | Value::Raw(s) => out.push_str(&quote(s)),
+        Value::Null => out.push_str("null"),
+        Value::RetVal(v) => encode_into(v, out)?,
+        Value::Array(arr) => {
+            out.push('[');
+            for (i, el) in arr.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                encode_into(el, out)?;
+            }
+            out.push(']');
+        },
+        Value::Hash(hash) => {
+            let mut keys = hash.keys().collect::<Vec<&String>>();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&quote(key));
+                out.push(':');
+                encode_into(&hash[key], out)?;
+            }
+            out.push('}');
+        },
+        other => return Err(format!("{} can't be encoded as JSON", other)),
+    }
+    Ok(())
+}
+
+/// Quotes a string as a JSON string literal.
+pub fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Parses a JSON document. Numbers must be integers that fit an `Int`.
+pub fn decode(input: &str) -> Result<Value, String> {
+    decode_with(input, false)
+}
+
+/// Parses a JSON document like `decode`, but reads numbers that aren't integers fitting an `Int`
+/// as null rather than failing, for documents where those can be ignored.
+pub fn decode_lossy(input: &str) -> Result<Value, String> {
+    decode_with(input, true)
+}
+
+fn decode_with(input: &str, lossy: bool) -> Result<Value, String> {
+    let mut decoder = Decoder{ chars: input.chars().peekable(), pos: 0, depth: 0, lossy };
+    let val = decoder.value()?;
+    decoder.skip_whitespace();
+    match decoder.chars.peek().cloned() {
+        None => Ok(val),
+        Some(c) => Err(decoder.error(&format!("unexpected {:?} after the value", c))),
+    }
+}
+
+struct Decoder<'a> {
+    chars: Peekable<Chars<'a>>,
+    /// Number of characters read so far, for error messages
+    pos: usize,
+    depth: usize,
+    /// Whether numbers that aren't supported are read as null
+    lossy: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("Invalid JSON at character {}: {}", self.pos, message)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(self.error(&format!("expected {:?}, got {:?}", expected, c))),
+            None => Err(self.error(&format!("expected {:?}, got the end of the input", expected))),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, val: Value) -> Result<Value, String> {
+        for c in word.chars() {
+            self.expect(c)?;
+        }
+        Ok(val)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('n') => self.keyword("null", Value::Null),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('"') => self.string().map(Value::Str),
+            Some('[') => self.nested(Decoder::array),
+            Some('{') => self.nested(Decoder::object),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(&c) => Err(self.error(&format!("unexpected {:?}", c))),
+            None => Err(self.error("unexpected end of the input")),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Decoder<'a>) -> Result<Value, String>) -> Result<Value, String> {
+        // As deeply as values may nest, so that input can't exhaust the stack
+        if self.depth == MAX_NESTING {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let val = parse(self);
+        self.depth -= 1;
+        val
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let mut num = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' || c.is_ascii_digit() {
+                num.push(c);
+                self.next();
+            } else {
+                break;
+            }
+        }
+        match num.parse::<i32>() {
+            Ok(i) => Ok(Value::Int(i)),
+            Err(_) if self.lossy && num.parse::<f64>().is_ok() => Ok(Value::Null),
+            Err(_) => Err(self.error(&format!("{} is not a supported integer", num))),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => s.push(self.unicode_escape()?),
+                    _ => return Err(self.error("invalid escape sequence")),
+                },
+                Some(c) if (c as u32) < 0x20 => return Err(self.error("unescaped control character in string")),
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// Reads the code point of a `\u` escape, combining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            self.expect('\\')?;
+            self.expect('u')?;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("invalid surrogate pair"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        ::std::char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.next().and_then(|c| c.to_digit(16)).ok_or_else(|| self.error("invalid unicode escape"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut arr = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.next();
+            return Ok(Value::Array(arr));
+        }
+        loop {
+            arr.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(arr)),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut hash = HashMap::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.next();
+            return Ok(Value::Hash(hash));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            hash.insert(key, self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Hash(hash)),
+                _ => return Err(self.error("expected ',' or '}' in object")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ encode, decode, decode_lossy };
+    use eval::Value::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_encode() {
+        let mut hash = HashMap::new();
+        hash.insert(String::from("b"), Array(vec![Int(1), Bool(true), Null]));
+        hash.insert(String::from("a"), Str(String::from("say \"hi\"\n")));
+        assert_eq!(encode(&Hash(hash)), Ok(String::from("{\"a\":\"say \\\"hi\\\"\\n\",\"b\":[1,true,null]}")));
+        assert!(encode(&Array(vec![FnDecl(vec![::ast::Param::new("x")], Box::new(::ast::Statement::BlockStatement(vec![])))])).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let val = decode(" {\"name\": \"caf\\u00e9 \\ud83d\\ude00\", \"tags\": [1, -2, false, null], \"nested\": {}} ").unwrap();
+        let mut hash = HashMap::new();
+        hash.insert(String::from("name"), Str(String::from("café 😀")));
+        hash.insert(String::from("tags"), Array(vec![Int(1), Int(-2), Bool(false), Null]));
+        hash.insert(String::from("nested"), Hash(HashMap::new()));
+        assert_eq!(val, Hash(hash));
+        assert_eq!(decode(&encode(&val).unwrap()), Ok(val));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode("[1, 2"), Err(String::from("Invalid JSON at character 5: expected ',' or ']' in array")));
+        assert!(decode("1.5").is_err());
+        assert!(decode("{\"a\" 1}").is_err());
+        assert!(decode("[1] 2").is_err());
+        assert!(decode("\"abc").is_err());
+        assert!(decode(&"[".repeat(10000)).is_err());
+    }
+
+    #[test]
+    fn test_decode_lossy() {
+        assert_eq!(decode_lossy("[1, 1.5, -2e3, 9999999999]"), Ok(Array(vec![Int(1), Null, Null, Null])));
+        assert!(decode_lossy("[1.2.3]").is_err());
+        assert!(decode_lossy("[-]").is_err());
+    }
+}
@@ -9,6 +9,7 @@ pub enum Token {
     Assign,
     Plus,
     Comma,
+    Ellipsis,
     Semicolon,
     Colon,
     Lparen,
@@ -33,12 +34,30 @@ pub enum Token {
     If,
     Else,
     Ret,
+    Throw,
+    Try,
+    Catch,
+    Finally,
     String(String),
+    /// Literal text of a template, never produced by lexing source code
+    Text(String),
+}
+
+/// Location of a token in the source, both 1-based.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
 }
 
 pub trait TokenLexer {
     fn init(&mut self) {}
     fn next_token(&mut self) -> Token;
+
+    /// Returns the next token along with where it starts, for lexers that keep track of it.
+    fn next_spanned(&mut self) -> (Token, Position) {
+        (self.next_token(), Position::default())
+    }
 }
 
 pub struct Lexer {
@@ -46,6 +65,8 @@ pub struct Lexer {
     pos: usize,
     read_pos: usize,
     ch: Option<char>,
+    line: usize,
+    col: usize,
     keywords: HashMap<&'static str, Token>,
 }
 
@@ -55,11 +76,7 @@ impl TokenLexer for Lexer {
     }
 
     fn next_token(&mut self) -> Token {
-        while self.ch.map_or(false, |c| {
-            c.is_whitespace()
-        }) {
-            self.read_char();
-        }
+        self.skip_whitespace();
         let mut read_next = true;
         let ret = self.ch.map_or(Token::Eof, |c| {
             match c {
@@ -89,6 +106,15 @@ impl TokenLexer for Lexer {
                         Token::Not
                     }
                 },
+                '.' => {
+                    if self.peek_char() == Some('.') && self.input.get(self.read_pos + 1) == Some(&'.') {
+                        self.read_char();
+                        self.read_char();
+                        Token::Ellipsis
+                    } else {
+                        Token::Illegal
+                    }
+                },
                 '-' => Token::Minus,
                 '/' => Token::Div,
                 '*' => Token::Mul,
@@ -105,7 +131,8 @@ impl TokenLexer for Lexer {
                         }
                     } else if c.is_numeric() {
                         read_next = false;
-                        Token::Int(self.read_num())
+                        // Literals too large for an int are left for the parser to report
+                        self.read_num().map_or(Token::Illegal, Token::Int)
                     } else {
                         Token::Illegal
                     }
@@ -117,6 +144,12 @@ impl TokenLexer for Lexer {
         }
         ret
     }
+
+    fn next_spanned(&mut self) -> (Token, Position) {
+        self.skip_whitespace();
+        let pos = self.char_pos();
+        (self.next_token(), pos)
+    }
 }
 
 impl Lexer {
@@ -130,7 +163,11 @@ impl Lexer {
         keywords.insert("if", Token::If);
         keywords.insert("else", Token::Else);
         keywords.insert("return", Token::Ret);
-        Lexer{ input: input.chars().collect::<Vec<char>>(), pos: 0, read_pos: 0, ch: None, keywords }
+        keywords.insert("throw", Token::Throw);
+        keywords.insert("try", Token::Try);
+        keywords.insert("catch", Token::Catch);
+        keywords.insert("finally", Token::Finally);
+        Lexer{ input: input.chars().collect::<Vec<char>>(), pos: 0, read_pos: 0, ch: None, line: 1, col: 0, keywords }
     }
 
     pub fn lex_str(input: &str) -> Vec<Token> {
@@ -141,6 +178,14 @@ impl Lexer {
 
     pub fn read_char(&mut self) {
         let nch = self.input.get(self.read_pos).map(|c| *c);
+        if nch.is_some() {
+            if self.ch == Some('\n') {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
         self.ch = nch.map(|c| {
             self.pos = self.read_pos;
             self.read_pos += 1;
@@ -148,6 +193,17 @@ impl Lexer {
         });
     }
 
+    /// Position of the current character.
+    pub fn char_pos(&self) -> Position {
+        Position{ line: self.line, col: self.col }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.ch.is_some_and(|c| c.is_whitespace()) {
+            self.read_char();
+        }
+    }
+
     pub fn get_char(&self) -> Option<char> {
         self.ch
     }
@@ -181,7 +237,7 @@ impl Lexer {
         ident
     }
 
-    fn read_num(&mut self) -> i32 {
+    fn read_num(&mut self) -> Option<i32> {
         let mut num_s = String::new();
         while let Some(c) = self.ch {
             if !(c.is_numeric()) {
@@ -190,7 +246,7 @@ impl Lexer {
             num_s.push(c);
             self.read_char();
         }
-        num_s.parse::<i32>().unwrap()
+        num_s.parse::<i32>().ok()
     }
 }
 
@@ -206,6 +262,27 @@ impl Iterator for Lexer {
     }
 }
 
+/// Writes `s` as the string literal `read_str` reads back as `s`, for whatever prints code.
+/// Literals have no escapes, so strings taken from them never hold a `"`.
+pub fn string_literal(s: &str) -> String {
+    debug_assert!(!s.contains('"'), "{:?} can't be written as a string literal", s);
+    format!("\"{}\"", s)
+}
+
+/// Splits a program into its tokens, each with where it starts.
+pub fn tokenize(input: &str) -> Vec<(Token, Position)> {
+    let mut lexer = Lexer::new(String::from(input));
+    lexer.init();
+    let mut tokens = Vec::new();
+    loop {
+        let (token, pos) = lexer.next_spanned();
+        if token == Token::Eof {
+            return tokens;
+        }
+        tokens.push((token, pos));
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::{ Lexer, Token, TokenLexer };
@@ -257,4 +334,33 @@ mod test {
         assert_eq!(tokens.len(), 73);
         assert!(!tokens.iter().any(|t| *t == Token::Illegal));
     }
+
+    #[test]
+    fn test_ellipsis() {
+        assert_eq!(Lexer::lex_str("f(...a)"), vec![Token::Ident(String::from("f")), Token::Lparen,
+            Token::Ellipsis, Token::Ident(String::from("a")), Token::Rparen]);
+        assert_eq!(Lexer::lex_str("a..b"), vec![Token::Ident(String::from("a"))]);
+    }
+
+    #[test]
+    fn test_int_range() {
+        assert_eq!(Lexer::lex_str("2147483647"), vec![Token::Int(2147483647)]);
+        let mut lex = Lexer::new(String::from("x 2147483648"));
+        lex.init();
+        assert_eq!((lex.next_token(), lex.next_token()), (Token::Ident(String::from("x")), Token::Illegal));
+    }
+
+    #[test]
+    fn test_positions() {
+        let mut lex = Lexer::new(String::from("let x = 5;\n  x + \"a b\""));
+        lex.init();
+        let mut positions = Vec::new();
+        loop {
+            match lex.next_spanned() {
+                (Token::Eof, _) => break,
+                (_, pos) => positions.push((pos.line, pos.col)),
+            }
+        }
+        assert_eq!(positions, vec![(1, 1), (1, 5), (1, 7), (1, 9), (1, 10), (2, 3), (2, 5), (2, 7)]);
+    }
 }
@@ -1,9 +1,20 @@
 #[macro_use]
 extern crate lazy_static;
+extern crate signal_hook;
+extern crate rustyline;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate rustls_pemfile;
 
 pub mod lexer;
 pub mod repl;
 pub mod parser;
 pub mod ast;
+pub mod check;
 pub mod eval;
+pub mod escape;
+pub mod json;
+pub mod lsp;
+pub mod run;
 pub mod server;
@@ -0,0 +1,616 @@
+use ast::Program;
+use check;
+use check::{ Severity, Symbol };
+use eval;
+use eval::{ State, Value, BUILTINS };
+use json;
+use lexer::{ tokenize, Lexer, Position, Token };
+use parser;
+use parser::{ Parser, ParseError };
+use run;
+use server;
+use std::collections::HashMap;
+use std::io;
+use std::io::{ BufRead, Write };
+use std::panic;
+use std::panic::AssertUnwindSafe;
+
+/// Error codes of JSON-RPC
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const INTERNAL_ERROR: i32 = -32603;
+
+const SEVERITY_ERROR: i32 = 1;
+const SEVERITY_WARNING: i32 = 2;
+
+const COMPLETION_FUNCTION: i32 = 3;
+const COMPLETION_VARIABLE: i32 = 6;
+const COMPLETION_KEYWORD: i32 = 14;
+
+const KEYWORDS: &[&str] = &["let", "fn", "if", "else", "return", "true", "false", "null", "throw", "try", "catch", "finally"];
+
+type RpcResult = Result<Value, (i32, String)>;
+
+/// Speaks the Language Server Protocol over `input` and `output` until the client says to exit,
+/// returning the exit code the protocol asks for. Messages are JSON, in which only integers are
+/// supported, as they are by `json_decode`; other numbers are read as null.
+///
+/// Documents are synced in full. Those with `<%` tags are read as templates served by `serve`,
+/// the rest as programs.
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<i32> {
+    let mut server = Server{ output, documents: HashMap::new(), shut_down: false };
+    while let Some(body) = read_message(input)? {
+        let message = match json::decode_lossy(&body) {
+            Ok(message) => message,
+            Err(err) => {
+                server.respond(Value::Null, Err((PARSE_ERROR, err)))?;
+                continue;
+            },
+        };
+        let params = field(&message, &["params"]).cloned().unwrap_or(Value::Null);
+        match (field(&message, &["method"]), field(&message, &["id"])) {
+            // A bug handling one message shouldn't take the editor session down with it
+            (Some(Value::Str(method)), Some(id)) => {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| server.request(method, &params)))
+                    .unwrap_or_else(|_| Err((INTERNAL_ERROR, format!("The server failed to handle {}", method))));
+                server.respond(id.clone(), result)?;
+            },
+            (Some(Value::Str(method)), None) if method == "exit" => return Ok(if server.shut_down { 0 } else { 1 }),
+            (Some(Value::Str(method)), None) => if let Ok(result) = panic::catch_unwind(AssertUnwindSafe(|| server.notify(method, &params))) {
+                result?;
+            },
+            // Responses, the server not making requests of its own
+            (None, Some(_)) => {},
+            (_, id) => server.respond(id.cloned().unwrap_or(Value::Null), Err((INVALID_REQUEST, String::from("Messages need a method"))))?,
+        }
+    }
+    Ok(1)
+}
+
+struct Server<'a> {
+    output: &'a mut dyn Write,
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+}
+
+impl<'a> Server<'a> {
+    fn request(&mut self, method: &str, params: &Value) -> RpcResult {
+        if self.shut_down {
+            return Err((INVALID_REQUEST, String::from("The server is shutting down")));
+        }
+        match method {
+            "initialize" => Ok(initialize()),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            },
+            "textDocument/definition" => self.at_position(params).map(|(uri, document, pos)| document.definition(uri, pos)),
+            "textDocument/references" => {
+                let declarations = field(params, &["context", "includeDeclaration"]) == Some(&Value::Bool(true));
+                self.at_position(params).map(|(uri, document, pos)| document.references(uri, pos, declarations))
+            },
+            "textDocument/hover" => self.at_position(params).map(|(_, document, pos)| document.hover(pos)),
+            "textDocument/completion" => self.document(params).map(|(_, document)| document.completion()),
+            "textDocument/formatting" => self.document(params).map(|(_, document)| document.formatting()),
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method {}", method))),
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: &Value) -> io::Result<()> {
+        let uri = match field(params, &["textDocument", "uri"]) {
+            Some(Value::Str(uri)) => uri.clone(),
+            _ => return Ok(()),
+        };
+        let text = match method {
+            "textDocument/didOpen" => field(params, &["textDocument", "text"]),
+            // Changes are synced in full, so the last one has the whole text
+            "textDocument/didChange" => match field(params, &["contentChanges"]) {
+                Some(Value::Array(changes)) => changes.last().and_then(|change| field(change, &["text"])),
+                _ => None,
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return self.publish(&uri, Value::Array(Vec::new()));
+            },
+            _ => None,
+        };
+        match text {
+            Some(Value::Str(text)) => {
+                let document = Document::new(text.clone());
+                let diagnostics = document.diagnostics();
+                self.documents.insert(uri.clone(), document);
+                self.publish(&uri, diagnostics)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// The open document a request is about.
+    fn document<'b>(&self, params: &'b Value) -> Result<(&'b str, &Document), (i32, String)> {
+        match field(params, &["textDocument", "uri"]) {
+            Some(Value::Str(uri)) => match self.documents.get(uri) {
+                Some(document) => Ok((uri, document)),
+                None => Err((INVALID_PARAMS, format!("{} is not open", uri))),
+            },
+            _ => Err((INVALID_PARAMS, String::from("Missing textDocument.uri"))),
+        }
+    }
+
+    /// The open document a request is about, with the position in it the request is for.
+    fn at_position<'b>(&self, params: &'b Value) -> Result<(&'b str, &Document, Position), (i32, String)> {
+        let (uri, document) = self.document(params)?;
+        match field(params, &["position"]).and_then(|pos| document.position(pos)) {
+            Some(pos) => Ok((uri, document, pos)),
+            None => Err((INVALID_PARAMS, String::from("Missing position"))),
+        }
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Value) -> io::Result<()> {
+        write_message(self.output, &object(vec![
+            ("jsonrpc", string("2.0")),
+            ("method", string("textDocument/publishDiagnostics")),
+            ("params", object(vec![("uri", string(uri)), ("diagnostics", diagnostics)])),
+        ]))
+    }
+
+    fn respond(&mut self, id: Value, result: RpcResult) -> io::Result<()> {
+        let outcome = match result {
+            Ok(result) => ("result", result),
+            Err((code, message)) => ("error", object(vec![("code", Value::Int(code)), ("message", string(&message))])),
+        };
+        write_message(self.output, &object(vec![("jsonrpc", string("2.0")), ("id", id), outcome]))
+    }
+}
+
+fn initialize() -> Value {
+    let enabled = Value::Bool(true);
+    object(vec![
+        ("capabilities", object(vec![
+            // Full sync
+            ("textDocumentSync", Value::Int(1)),
+            ("definitionProvider", enabled.clone()),
+            ("referencesProvider", enabled.clone()),
+            ("hoverProvider", enabled.clone()),
+            ("completionProvider", object(Vec::new())),
+            ("documentFormattingProvider", enabled),
+        ])),
+        ("serverInfo", object(vec![("name", string("monkeylang")), ("version", string(env!("CARGO_PKG_VERSION")))])),
+    ])
+}
+
+/// An open file, analysed whenever it changes.
+struct Document {
+    text: String,
+    template: bool,
+    tokens: Vec<(Token, Position)>,
+    program: Result<Program, ParseError>,
+    /// Bindings of the program, none if it doesn't parse
+    symbols: Vec<Symbol>,
+}
+
+impl Document {
+    fn new(text: String) -> Document {
+        let template = text.contains("<%");
+        let (tokens, program) = if template {
+            (server::tokenize_template(&text).unwrap_or_default(), server::compile_template(&text))
+        } else {
+            let code = run::strip_shebang(&text);
+            (tokenize(code), Parser::new(&mut Lexer::new(String::from(code))).parse_program())
+        };
+        let globals = if template { server::GLOBALS } else { run::GLOBALS };
+        let symbols = program.as_ref().map(|program| check::symbols(program, globals)).unwrap_or_default();
+        Document{ text, template, tokens, program, symbols }
+    }
+
+    fn globals(&self) -> &'static [&'static str] {
+        if self.template { server::GLOBALS } else { run::GLOBALS }
+    }
+
+    fn diagnostics(&self) -> Value {
+        let diagnostic = |pos, severity, message: &str| object(vec![
+            ("range", self.range(pos)),
+            ("severity", Value::Int(severity)),
+            ("source", string("monkeylang")),
+            ("message", string(message)),
+        ]);
+        Value::Array(match &self.program {
+            Ok(program) => check::check(program, self.globals()).into_iter().map(|finding| {
+                let severity = match finding.lint.severity() {
+                    Severity::Error => SEVERITY_ERROR,
+                    Severity::Warning => SEVERITY_WARNING,
+                };
+                diagnostic(finding.pos, severity, &finding.message)
+            }).collect(),
+            Err(err) => vec![diagnostic(err.pos, SEVERITY_ERROR, &err.message)],
+        })
+    }
+
+    fn definition(&self, uri: &str, pos: Position) -> Value {
+        let locations = self.symbols_at(pos).into_iter()
+            .map(|symbol| location(uri, self.range(self.name_position(symbol))))
+            .collect::<Vec<Value>>();
+        if locations.is_empty() { Value::Null } else { Value::Array(locations) }
+    }
+
+    fn references(&self, uri: &str, pos: Position, declarations: bool) -> Value {
+        let mut found = Vec::new();
+        for symbol in self.symbols_at(pos) {
+            if declarations {
+                found.push(self.name_position(symbol));
+            }
+            found.extend(&symbol.references);
+        }
+        found.sort();
+        found.dedup();
+        Value::Array(found.into_iter().map(|pos| location(uri, self.range(pos))).collect())
+    }
+
+    fn hover(&self, pos: Position) -> Value {
+        let (name, start) = match self.ident_at(pos) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        let symbols = self.symbols_at(pos);
+        let text = match symbols.first() {
+            Some(symbol) if symbol.param => format!("```monkey\n(parameter) {}\n```", name),
+            Some(Symbol{ params: Some(params), .. }) => format!("```monkey\nlet {} = fn({})\n```", name, parser::format_params(params)),
+            Some(_) => format!("```monkey\nlet {}\n```", name),
+            None => match BUILTINS.iter().find(|builtin| builtin.0 == name) {
+                Some(&(_, min, max)) => {
+                    let signature = match State::new().get(&String::from(name)) {
+                        Some(Value::FnDecl(params, _)) => format!("{}({})", name, parser::format_params(params)),
+                        _ => format!("builtin {}", name),
+                    };
+                    format!("```monkey\n{}\n```\nTakes {}.", signature, eval::arguments(min, max))
+                },
+                None if self.globals().contains(&name) => format!("```monkey\n(global) {}\n```", name),
+                None => return Value::Null,
+            },
+        };
+        object(vec![
+            ("contents", object(vec![("kind", string("markdown")), ("value", string(&text))])),
+            ("range", self.range(start)),
+        ])
+    }
+
+    /// Offers the names used or bound in the document along with the builtins and keywords,
+    /// leaving it to the client to narrow them down to what is being typed.
+    fn completion(&self) -> Value {
+        let mut kinds = HashMap::new();
+        for (token, _) in &self.tokens {
+            if let Token::Ident(name) = token {
+                kinds.insert(name.as_str(), COMPLETION_VARIABLE);
+            }
+        }
+        for symbol in &self.symbols {
+            let kind = if symbol.params.is_some() { COMPLETION_FUNCTION } else { COMPLETION_VARIABLE };
+            kinds.insert(symbol.name.as_str(), kind);
+        }
+        for global in self.globals() {
+            kinds.insert(global, COMPLETION_VARIABLE);
+        }
+        for builtin in BUILTINS {
+            kinds.insert(builtin.0, COMPLETION_FUNCTION);
+        }
+        for keyword in KEYWORDS {
+            kinds.insert(keyword, COMPLETION_KEYWORD);
+        }
+        let mut items = kinds.into_iter().collect::<Vec<(&str, i32)>>();
+        items.sort();
+        Value::Array(items.into_iter()
+            .map(|(label, kind)| object(vec![("label", string(label)), ("kind", Value::Int(kind))]))
+            .collect())
+    }
+
+    /// Replaces the whole document with its canonical form. Templates aren't formatted, nor are
+    /// programs that don't parse.
+    fn formatting(&self) -> Value {
+        let program = match &self.program {
+            Ok(program) if !self.template => program,
+            _ => return Value::Null,
+        };
+        let shebang = &self.text[..self.text.len() - run::strip_shebang(&self.text).len()];
+        let formatted = if shebang.is_empty() {
+            parser::format(program)
+        } else {
+            format!("{}\n{}", shebang, parser::format(program))
+        };
+        if formatted == self.text {
+            return Value::Array(Vec::new());
+        }
+        let lines = self.text.split('\n').count();
+        let end = self.lsp_position(Position{ line: lines, col: self.line(lines).chars().count() + 1 });
+        let start = object(vec![("line", Value::Int(0)), ("character", Value::Int(0))]);
+        Value::Array(vec![object(vec![
+            ("range", object(vec![("start", start), ("end", end)])),
+            ("newText", string(&formatted)),
+        ])])
+    }
+
+    fn line(&self, line: usize) -> &str {
+        self.text.split('\n').nth(line.saturating_sub(1)).unwrap_or("")
+    }
+
+    /// The name, number or keyword starting at a position.
+    fn word_at(&self, pos: Position) -> &str {
+        let line = self.line(pos.line);
+        let start = line.char_indices().nth(pos.col.saturating_sub(1)).map_or(line.len(), |(i, _)| i);
+        let rest = &line[start..];
+        let end = rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len());
+        &rest[..end]
+    }
+
+    /// The range of the word starting at a position, or of the single character there.
+    fn range(&self, pos: Position) -> Value {
+        let len = self.word_at(pos).chars().count().max(1);
+        object(vec![
+            ("start", self.lsp_position(pos)),
+            ("end", self.lsp_position(Position{ line: pos.line, col: pos.col + len })),
+        ])
+    }
+
+    /// Converts a position to the protocol's, which counts from 0 and in UTF-16 code units.
+    fn lsp_position(&self, pos: Position) -> Value {
+        let character = self.line(pos.line).chars().take(pos.col.saturating_sub(1)).map(char::len_utf16).sum::<usize>();
+        object(vec![("line", int(pos.line.saturating_sub(1))), ("character", int(character))])
+    }
+
+    /// Converts a position of the protocol.
+    fn position(&self, pos: &Value) -> Option<Position> {
+        let (line, character) = match (field(pos, &["line"]), field(pos, &["character"])) {
+            (Some(&Value::Int(line)), Some(&Value::Int(character))) if line >= 0 && character >= 0 => (line as usize + 1, character as usize),
+            _ => return None,
+        };
+        let mut units = 0;
+        let mut col = 1;
+        for c in self.line(line).chars() {
+            if units >= character {
+                break;
+            }
+            units += c.len_utf16();
+            col += 1;
+        }
+        Some(Position{ line, col })
+    }
+
+    /// The name at a position, including right after it, and where it starts. Names templates
+    /// print their text and output with aren't in the text, so they are left out.
+    fn ident_at(&self, pos: Position) -> Option<(&str, Position)> {
+        self.tokens.iter().find_map(|(token, start)| match token {
+            Token::Ident(name) if start.line == pos.line && start.col <= pos.col
+                && pos.col <= start.col + name.chars().count() && self.word_at(*start) == name => Some((name.as_str(), *start)),
+            _ => None,
+        })
+    }
+
+    /// The bindings the name at a position makes or refers to.
+    fn symbols_at(&self, pos: Position) -> Vec<&Symbol> {
+        match self.ident_at(pos) {
+            Some((_, start)) => self.symbols.iter()
+                .filter(|symbol| symbol.references.contains(&start) || self.name_position(symbol) == start)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Where the name of a binding is written, following its `let` or `fn`, or for a `catch`,
+    /// preceding its block.
+    fn name_position(&self, symbol: &Symbol) -> Position {
+        let start = self.tokens.iter().position(|(_, pos)| *pos == symbol.pos).unwrap_or(self.tokens.len());
+        if let [(Token::Catch, _), (Token::Lparen, _), (Token::Ident(name), pos), (Token::Rparen, _), (Token::Lbrace, _)]
+            = &self.tokens[start.saturating_sub(4)..(start + 1).min(self.tokens.len())] {
+            if *name == symbol.name {
+                return *pos;
+            }
+        }
+        self.tokens.iter()
+            .skip_while(|(_, pos)| *pos != symbol.pos)
+            .find(|(token, _)| matches!(token, Token::Ident(name) if *name == symbol.name))
+            .map_or(symbol.pos, |(_, pos)| *pos)
+    }
+}
+
+fn location(uri: &str, range: Value) -> Value {
+    object(vec![("uri", string(uri)), ("range", range)])
+}
+
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Hash(fields.into_iter().map(|(key, value)| (String::from(key), value)).collect())
+}
+
+fn string(s: &str) -> Value {
+    Value::Str(String::from(s))
+}
+
+fn int(n: usize) -> Value {
+    Value::Int(n as i32)
+}
+
+/// Looks up a value nested in objects.
+fn field<'a>(value: &'a Value, path: &[&str]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, key| match value {
+        Value::Hash(hash) => hash.get(*key),
+        _ => None,
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the body of the next message, which follows a `Content-Length` header. Returns `None`
+/// once the input ends.
+fn read_message(input: &mut dyn BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse::<usize>().map_err(|_| invalid_data("Invalid Content-Length"))?);
+            }
+        }
+    }
+    let mut body = vec![0; length.ok_or_else(|| invalid_data("Missing Content-Length"))?];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(|_| invalid_data("Messages must be UTF-8"))
+}
+
+fn write_message(output: &mut dyn Write, message: &Value) -> io::Result<()> {
+    let body = json::encode(message).map_err(|err| invalid_data(&err))?;
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ read_message, serve, field };
+    use eval::Value;
+    use json;
+    use std::io::Cursor;
+
+    /// Runs a session with the given messages, returning its exit code and the messages sent back.
+    fn session(messages: &[String]) -> (i32, Vec<Value>) {
+        let input = messages.iter().map(|body| format!("Content-Length: {}\r\n\r\n{}", body.len(), body)).collect::<String>();
+        let mut output = Vec::new();
+        let code = serve(&mut Cursor::new(input.into_bytes()), &mut output).unwrap();
+        let mut output = Cursor::new(output);
+        let mut replies = Vec::new();
+        while let Some(body) = read_message(&mut output).unwrap() {
+            replies.push(json::decode(&body).unwrap());
+        }
+        (code, replies)
+    }
+
+    fn open(uri: &str, text: &str) -> String {
+        format!("{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didOpen\",\"params\":{{\"textDocument\":{{\"uri\":\"{}\",\"languageId\":\"monkey\",\"version\":1,\"text\":{}}}}}}}",
+            uri, json::quote(text))
+    }
+
+    fn request(id: i32, method: &str, uri: &str, extra: &str) -> String {
+        format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"method\":\"{}\",\"params\":{{\"textDocument\":{{\"uri\":\"{}\"}}{}}}}}", id, method, uri, extra)
+    }
+
+    fn at(line: i32, character: i32) -> String {
+        format!(",\"position\":{{\"line\":{},\"character\":{}}}", line, character)
+    }
+
+    fn reply(replies: &[Value], id: i32) -> Value {
+        replies.iter().find(|reply| field(reply, &["id"]) == Some(&Value::Int(id))).and_then(|reply| field(reply, &["result"])).cloned().unwrap()
+    }
+
+    /// The ranges of locations as (line, start, end) tuples.
+    fn ranges(locations: &Value) -> Vec<(i32, i32, i32)> {
+        let int = |location: &Value, path: &[&str]| match field(location, path) {
+            Some(&Value::Int(i)) => i,
+            _ => panic!("missing {:?}", path),
+        };
+        match locations {
+            Value::Array(locations) => locations.iter().map(|location| (
+                int(location, &["range", "start", "line"]),
+                int(location, &["range", "start", "character"]),
+                int(location, &["range", "end", "character"]),
+            )).collect(),
+            other => panic!("not an array: {}", other),
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let uri = "file:///a.ml";
+        let code = "let add = fn(a, b) { a + b };\nlet é = add(1, 2);\nprintln(\"😀\", add(é, 1)) ; let unused = 1;\ntry { 1 } catch (err) { err }\n";
+        let (code, replies) = session(&[
+            String::from("{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{\"capabilities\":{}}}"),
+            String::from("{\"jsonrpc\":\"2.0\",\"method\":\"initialized\",\"params\":{}}"),
+            open(uri, code),
+            request(2, "textDocument/definition", uri, &at(1, 9)),
+            request(3, "textDocument/references", uri, &format!("{},\"context\":{{\"includeDeclaration\":true}}", at(0, 5))),
+            request(4, "textDocument/hover", uri, &at(0, 21)),
+            request(5, "textDocument/hover", uri, &at(2, 2)),
+            request(6, "textDocument/hover", uri, &at(2, 40)),
+            request(7, "textDocument/completion", uri, ""),
+            request(8, "textDocument/formatting", uri, ""),
+            request(9, "textDocument/unknown", uri, ""),
+            request(11, "textDocument/definition", uri, &at(3, 25)),
+            String::from("{\"jsonrpc\":\"2.0\",\"id\":10,\"method\":\"shutdown\"}"),
+            String::from("{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}"),
+        ]);
+        assert_eq!(code, 0);
+        assert_eq!(field(&reply(&replies, 1), &["capabilities", "hoverProvider"]), Some(&Value::Bool(true)));
+
+        let diagnostics = replies.iter().find(|reply| field(reply, &["method"]).is_some()).unwrap();
+        let diagnostics = field(diagnostics, &["params", "diagnostics"]).unwrap();
+        // Columns after the emoji are off by one in UTF-16
+        assert_eq!(ranges(diagnostics), vec![(2, 27, 30)]);
+        assert!(diagnostics.to_string().contains("unused is never used"));
+
+        assert_eq!(ranges(&reply(&replies, 2)), vec![(0, 4, 7)]);
+        assert_eq!(ranges(&reply(&replies, 3)), vec![(0, 4, 7), (1, 8, 11), (2, 14, 17)]);
+        let hover = |id| match field(&reply(&replies, id), &["contents", "value"]) {
+            Some(Value::Str(text)) => text.clone(),
+            other => panic!("no hover: {:?}", other),
+        };
+        assert_eq!(hover(4), "```monkey\n(parameter) a\n```");
+        assert_eq!(hover(5), "```monkey\nbuiltin println\n```\nTakes any number of arguments.");
+        assert_eq!(reply(&replies, 6), Value::Null);
+
+        let completion = reply(&replies, 7).to_string();
+        assert!(["add", "é", "map", "args", "return"].iter().all(|label| completion.contains(&format!("label: {}", label))));
+
+        let edits = reply(&replies, 8);
+        assert_eq!(ranges(&edits), vec![(0, 0, 0)]);
+        assert!(edits.to_string().contains("add(é, 1));\nlet unused = 1;\n"));
+
+        let error = replies.iter().find(|reply| field(reply, &["id"]) == Some(&Value::Int(9))).unwrap();
+        assert_eq!(field(error, &["error", "code"]), Some(&Value::Int(-32601)));
+        assert_eq!(ranges(&reply(&replies, 11)), vec![(3, 17, 20)]);
+    }
+
+    #[test]
+    fn test_invalid_document() {
+        let uri = "file:///big.ml";
+        let (code, replies) = session(&[
+            open(uri, "let x = 99999999999;\nx"),
+            request(1, "textDocument/hover", uri, &at(1, 0)),
+            // Options may hold numbers that aren't ints, which shouldn't fail the request
+            request(2, "textDocument/formatting", uri, ",\"options\":{\"tabSize\":4.5,\"x\":1e40}"),
+            String::from("{\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"shutdown\"}"),
+            String::from("{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}"),
+        ]);
+        assert_eq!(code, 0);
+        // The literal is too large for an int
+        let diagnostics = field(&replies[0], &["params", "diagnostics"]).unwrap();
+        assert_eq!(ranges(diagnostics), vec![(0, 8, 19)]);
+        for id in 1..4 {
+            assert!(replies.iter().any(|reply| field(reply, &["id"]) == Some(&Value::Int(id))), "no reply to {}", id);
+        }
+    }
+
+    #[test]
+    fn test_template() {
+        let uri = "file:///page.ml";
+        let page = "<h1><%= title %></h1>\n<% let title = \"Hi\"; let f = fn(x) x -%>\n<%= f(get[\"a\"]) %>";
+        let (code, replies) = session(&[
+            open(uri, page),
+            request(1, "textDocument/definition", uri, &at(2, 4)),
+            request(2, "textDocument/hover", uri, &at(0, 1)),
+            request(3, "textDocument/formatting", uri, ""),
+            String::from("{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}"),
+        ]);
+        // Exiting without a shutdown request first
+        assert_eq!(code, 1);
+        let diagnostics = field(&replies[0], &["params", "diagnostics"]).unwrap();
+        assert_eq!(ranges(diagnostics), vec![(0, 8, 13), (1, 3, 6)]);
+        assert_eq!(ranges(&reply(&replies, 1)), vec![(1, 25, 26)]);
+        assert_eq!(reply(&replies, 2), Value::Null);
+        assert_eq!(reply(&replies, 3), Value::Null);
+    }
+}
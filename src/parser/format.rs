@@ -0,0 +1,392 @@
+use ast::{ Expression, Statement, Param, Program };
+use lexer::string_literal;
+use super::OpPrecedence;
+
+const INDENT: &str = "    ";
+
+/// Prints a program back as source in the canonical style: one statement per line, blocks
+/// indented by four spaces, single spaces around operators and only the parentheses needed to
+/// keep the program parsing the same. The language has no comments yet, so there are none to keep.
+pub fn format(program: &Program) -> String {
+    let statements = program.statements().iter().map(|statement| &**statement).collect::<Vec<&Statement>>();
+    let mut out = String::new();
+    let mut prev_multiline = false;
+    for (i, text) in sequence(&statements, 0, false).iter().enumerate() {
+        // Statements spanning several lines, such as functions, are set apart by a blank line
+        let multiline = text.contains('\n');
+        if i > 0 && (multiline || prev_multiline) {
+            out.push('\n');
+        }
+        out.push_str(text);
+        out.push('\n');
+        prev_multiline = multiline;
+    }
+    out
+}
+
+/// Formats the parameters of a function, as in `a, b = 1, ...rest`.
+pub fn format_params(params: &[Param]) -> String {
+    param_list(params, 0)
+}
+
+/// Formats statements following one another, each with the semicolon it needs.
+fn sequence(statements: &[&Statement], indent: usize, in_block: bool) -> Vec<String> {
+    // Going backwards, as how a statement ends depends on how the next one starts
+    let mut texts: Vec<String> = Vec::new();
+    for (i, s) in statements.iter().enumerate().rev() {
+        let text = statement(s, indent, false);
+        let last = i + 1 == statements.len();
+        let e = match s {
+            Statement::BlockStatement(_) | Statement::Try(..) | Statement::Text(_) => {
+                texts.push(text);
+                continue;
+            },
+            Statement::Let(_, e) | Statement::Ret(e) | Statement::ExprStatement(e) | Statement::Throw(e) => e,
+        };
+        // Whether the next statement would be read as carrying on the expression
+        let carries_on = texts.last().is_some_and(|next| next.starts_with(['(', '[', '-']));
+        let text = if carries_on && has_unbraced_body(e) {
+            // The semicolon would only end the body, so the expression has to be closed off
+            format!("{};", statement(s, indent, true))
+        } else {
+            let semicolon = match s {
+                // The last expression of a block is its value. An `if` or function ending in a
+                // block can go without one too.
+                Statement::ExprStatement(_) if in_block && last => false,
+                Statement::ExprStatement(e) => carries_on
+                    || !(matches!(e, Expression::If(..) | Expression::FnDecl(..)) && text.ends_with('}')),
+                _ => true,
+            };
+            if semicolon { format!("{};", text) } else { text }
+        };
+        texts.push(text);
+    }
+    texts.reverse();
+    texts
+}
+
+/// Formats a statement, with its expression in parentheses if `parenthesized` is set.
+fn statement(statement: &Statement, indent: usize, parenthesized: bool) -> String {
+    match statement {
+        Statement::Let(name, e) => format!("let {} = {}", name, parens(expression(e, indent), parenthesized)),
+        Statement::Ret(e) => format!("return {}", parens(expression(e, indent), parenthesized)),
+        Statement::Throw(e) => format!("throw {}", parens(expression(e, indent), parenthesized)),
+        Statement::BlockStatement(statements) => block(statements, indent),
+        Statement::Try(try_body, handler, finally) => {
+            let mut out = format!("try {}", body(try_body, indent, false));
+            if let Some((name, handler)) = handler {
+                out.push_str(&format!(" catch ({}) {}", name, body(handler, indent, false)));
+            }
+            if let Some(finally) = finally {
+                out.push_str(&format!(" finally {}", body(finally, indent, false)));
+            }
+            out
+        },
+        // A `{` starting a statement opens a block, so a hash has to be told apart
+        Statement::ExprStatement(e) => parens(expression(e, indent), parenthesized || matches!(leftmost(e), Expression::Hash(_))),
+        // Only templates have text, which is written as it is between the tags around it
+        Statement::Text(text) => format!("%>{}<%", text),
+    }
+}
+
+fn block(statements: &[Statement], indent: usize) -> String {
+    if statements.is_empty() {
+        return String::from("{}");
+    }
+    let statements = statements.iter().collect::<Vec<&Statement>>();
+    let mut out = String::from("{\n");
+    for text in sequence(&statements, indent + 1, true) {
+        out.push_str(&INDENT.repeat(indent + 1));
+        out.push_str(&text);
+        out.push('\n');
+    }
+    out.push_str(&INDENT.repeat(indent));
+    out.push('}');
+    out
+}
+
+/// Formats the body of an `if` or function. A body followed by `else` that ends in an `if` without
+/// one is put in parentheses, or the `else` would be taken as that `if`'s.
+fn body(body: &Statement, indent: usize, before_else: bool) -> String {
+    statement(body, indent, before_else && is_open(body))
+}
+
+/// Whether an expression ends in the body of an `if` or function that isn't a block, which would
+/// take in a semicolon following it.
+fn has_unbraced_body(e: &Expression) -> bool {
+    let unbraced = |statement: &Statement| !matches!(statement, Statement::BlockStatement(_));
+    match e {
+        Expression::If(_, consequence, alternative) if is_empty_block(alternative) => unbraced(consequence),
+        Expression::If(_, _, alternative) => unbraced(alternative),
+        Expression::FnDecl(_, body) => unbraced(body),
+        _ => false,
+    }
+}
+
+/// Whether a statement ends in an `if` an `else` could still be added to.
+fn is_open(statement: &Statement) -> bool {
+    match statement {
+        Statement::Let(_, e) | Statement::Ret(e) | Statement::ExprStatement(e) | Statement::Throw(e) => match e {
+            Expression::If(_, _, alternative) => is_empty_block(alternative) || is_open(alternative),
+            Expression::FnDecl(_, body) => is_open(body),
+            _ => false,
+        },
+        Statement::BlockStatement(_) | Statement::Try(..) | Statement::Text(_) => false,
+    }
+}
+
+/// An `if` without `else` is parsed with an empty block in its place.
+fn is_empty_block(statement: &Statement) -> bool {
+    matches!(statement, Statement::BlockStatement(statements) if statements.is_empty())
+}
+
+fn expression(e: &Expression, indent: usize) -> String {
+    match e {
+        Expression::Int(i) => i.to_string(),
+        Expression::Ident(name) => name.clone(),
+        Expression::String(s) => string_literal(s),
+        Expression::True => String::from("true"),
+        Expression::False => String::from("false"),
+        Expression::Null => String::from("null"),
+        Expression::Neg(operand) => format!("-{}", operand_of(operand, OpPrecedence::Prefix, indent)),
+        Expression::Not(operand) => format!("!{}", operand_of(operand, OpPrecedence::Prefix, indent)),
+        Expression::If(condition, consequence, alternative) => {
+            let has_else = !is_empty_block(alternative);
+            let mut out = format!("if ({}) {}", expression(condition, indent), body(consequence, indent, has_else));
+            if has_else {
+                out.push_str(" else ");
+                out.push_str(&body(alternative, indent, false));
+            }
+            out
+        },
+        Expression::FnDecl(params, fn_body) => format!("fn({}) {}", param_list(params, indent), body(fn_body, indent, false)),
+        Expression::Call(function, args) => format!("{}({})", operand_of(function, OpPrecedence::Call, indent), list(args, indent)),
+        Expression::Array(elems) => format!("[{}]", list(elems, indent)),
+        Expression::Index(array, index) => format!("{}[{}]", operand_of(array, OpPrecedence::Call, indent), expression(index, indent)),
+        Expression::Hash(pairs) => format!("{{{}}}", pairs.iter()
+            .map(|(key, value)| format!("{}: {}", expression(key, indent), expression(value, indent)))
+            .collect::<Vec<String>>().join(", ")),
+        Expression::Spread(operand) => format!("...{}", expression(operand, indent)),
+        _ => {
+            let (left, op, right) = infix(e).unwrap();
+            let prec = precedence(e);
+            // Operators are left associative, so the right operand needs parentheses at the same precedence
+            format!("{} {} {}",
+                parens(expression(left, indent), precedence(left) < prec), op,
+                parens(expression(right, indent), precedence(right) <= prec))
+        },
+    }
+}
+
+fn infix(e: &Expression) -> Option<(&Expression, &'static str, &Expression)> {
+    Some(match e {
+        Expression::Plus(left, right) => (left, "+", right),
+        Expression::Minus(left, right) => (left, "-", right),
+        Expression::Div(left, right) => (left, "/", right),
+        Expression::Mul(left, right) => (left, "*", right),
+        Expression::Eq(left, right) => (left, "==", right),
+        Expression::Ne(left, right) => (left, "!=", right),
+        Expression::Lt(left, right) => (left, "<", right),
+        Expression::Gt(left, right) => (left, ">", right),
+        _ => return None,
+    })
+}
+
+/// How tightly an expression holds together, compared to the operator it is an operand of.
+/// The body of an `if` or function would take in anything following it, so they rank lowest.
+fn precedence(e: &Expression) -> OpPrecedence {
+    match e {
+        Expression::Eq(..) | Expression::Ne(..) => OpPrecedence::Eq,
+        Expression::Lt(..) | Expression::Gt(..) => OpPrecedence::LtGt,
+        Expression::Plus(..) | Expression::Minus(..) => OpPrecedence::Sum,
+        Expression::Mul(..) | Expression::Div(..) => OpPrecedence::Prod,
+        Expression::Neg(_) | Expression::Not(_) => OpPrecedence::Prefix,
+        Expression::If(..) | Expression::FnDecl(..) => OpPrecedence::Lowest,
+        // Calls and indexing chain onto each other, as does anything else they apply to
+        _ => OpPrecedence::Index,
+    }
+}
+
+fn operand_of(e: &Expression, min: OpPrecedence, indent: usize) -> String {
+    parens(expression(e, indent), precedence(e) < min)
+}
+
+/// The expression a formatted expression starts with.
+fn leftmost(e: &Expression) -> &Expression {
+    match infix(e) {
+        Some((left, _, _)) if precedence(left) >= precedence(e) => leftmost(left),
+        Some(_) => e,
+        None => match e {
+            Expression::Call(target, _) | Expression::Index(target, _) if precedence(target) >= OpPrecedence::Call => leftmost(target),
+            _ => e,
+        },
+    }
+}
+
+fn list(exprs: &[Expression], indent: usize) -> String {
+    exprs.iter().map(|e| expression(e, indent)).collect::<Vec<String>>().join(", ")
+}
+
+fn param_list(params: &[Param], indent: usize) -> String {
+    params.iter().map(|param| match &param.default {
+        _ if param.rest => format!("...{}", param.name),
+        Some(default) => format!("{} = {}", param.name, expression(default, indent)),
+        None => param.name.clone(),
+    }).collect::<Vec<String>>().join(", ")
+}
+
+fn parens(text: String, needed: bool) -> String {
+    if needed { format!("({})", text) } else { text }
+}
+
+#[cfg(test)]
+mod test {
+    use super::format;
+    use ast::{ Expression, Statement, Param, Program };
+    use lexer::Lexer;
+    use parser::Parser;
+
+    fn parse(code: &str) -> Program {
+        Parser::new(&mut Lexer::new(String::from(code))).parse_program().unwrap()
+    }
+
+    fn assert_round_trip(program: &Program) {
+        let formatted = format(program);
+        let reparsed = Parser::new(&mut Lexer::new(formatted.clone())).parse_program()
+            .unwrap_or_else(|err| panic!("{} in\n{}", err, formatted));
+        assert_eq!(reparsed.statements(), program.statements(), "formatted as\n{}", formatted);
+        assert_eq!(format(&reparsed), formatted);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format(&parse("let x=1;let add=fn(a,b){let c=a+b;c*2}")),
+            "let x = 1;\n\nlet add = fn(a, b) {\n    let c = a + b;\n    c * 2\n};\n");
+        assert_eq!(format(&parse("((1+2))*3-(4-5)-6/(7*8); -(a+b); (-a)[0]; -b[0]; f(x)[1](2); (fn(x){x})(1)")),
+            "(1 + 2) * 3 - (4 - 5) - 6 / (7 * 8);\n-(a + b);\n(-a)[0];\n-b[0];\nf(x)[1](2);\n\n(fn(x) {\n    x\n})(1);\n");
+        assert_eq!(format(&parse("if(x>1){1}else{if(y){2}}")),
+            "if (x > 1) {\n    1\n} else {\n    if (y) {\n        2\n    }\n}\n");
+        assert_eq!(format(&parse("if (a) (if (b) 1) else 2; let y = 2; ({\"a\": [1, 2]})[\"a\"]; return !(1 < 2) == true")),
+            "if (a) (if (b) 1) else 2;\nlet y = 2;\n({\"a\": [1, 2]}[\"a\"]);\nreturn !(1 < 2) == true;\n");
+        assert_eq!(format(&parse("if (a) { 1 }; (x); if (b) {} else { }")),
+            "if (a) {\n    1\n}\n\nx;\nif (b) {}\n");
+        assert_eq!(format(&parse("let f=fn(a,b=1+2,...c){g(a,...c)}")),
+            "let f = fn(a, b = 1 + 2, ...c) {\n    g(a, ...c)\n};\n");
+        assert_eq!(format(&parse("try{f()}catch(e){throw e}finally{g()}; x")),
+            "try {\n    f()\n} catch (e) {\n    throw e;\n} finally {\n    g()\n}\n\nx;\n");
+        assert_eq!(format(&parse("")), "");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sources = vec![
+            "let f = fn(x) { let res = if (x > 1) { f(x - 1) + f(x - 2) } else { 1 } return res };",
+            "map([1, 2, 3], fn(x) x * 2); let g = fn() fn(y) y;",
+            "a - (b - c) + -(-d) * !e; 1 < 2 == (3 > 4) != false",
+            "if (a) let x = if (b) 1 else 2; else return fn() if (c) 3;",
+            "{ let x = 1; { x } } (x)[0]; -x;",
+            "({\"k\": fn(a) { a }, 1: null})[1]",
+            "let f = fn(x) x; (f)(1); if (a) return 1; [2]; fn() if (b) {}; -1",
+            "let g = fn(a, b = fn(x) x, c = if (a) 1 else 2, ...rest) b(...rest, ...[c]); g(...[1], 2)",
+            "try { throw {\"kind\": \"NotFound\"} } catch (e) { e } (x); let f = fn() try { 1 } finally {}; (y); throw if (a) 1;",
+            "let page = \"<% print(1) %>\\n\n<%= x %>\r\n\"; let h = {\"a\\\\b\": \"\"};",
+        ];
+        for source in sources {
+            assert_round_trip(&parse(source));
+        }
+    }
+
+    /// Generates random programs from a fixed seed, so that failures can be reproduced.
+    struct Generator(u64);
+
+    impl Generator {
+        fn next(&mut self, n: usize) -> usize {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn name(&mut self) -> String {
+            String::from(["a", "b", "xs", "fib"][self.next(4)])
+        }
+
+        fn expression(&mut self, depth: usize) -> Expression {
+            let boxed = |g: &mut Generator| Box::new(g.expression(depth - 1));
+            if depth == 0 {
+                return match self.next(6) {
+                    0 => Expression::Int(self.next(100) as i32),
+                    1 => Expression::String(["", "a b", "<p>"][self.next(3)].to_string()),
+                    2 => Expression::True,
+                    3 => Expression::Null,
+                    _ => Expression::Ident(self.name()),
+                };
+            }
+            match self.next(16) {
+                0 => Expression::Plus(boxed(self), boxed(self)),
+                1 => Expression::Minus(boxed(self), boxed(self)),
+                2 => Expression::Mul(boxed(self), boxed(self)),
+                3 => Expression::Div(boxed(self), boxed(self)),
+                4 => Expression::Eq(boxed(self), boxed(self)),
+                5 => Expression::Lt(boxed(self), boxed(self)),
+                6 => Expression::Neg(boxed(self)),
+                7 => Expression::Not(boxed(self)),
+                8 => {
+                    let alternative = if self.next(2) == 0 { Statement::BlockStatement(Vec::new()) } else { self.statement(depth - 1) };
+                    Expression::If(boxed(self), Box::new(self.statement(depth - 1)), Box::new(alternative))
+                },
+                9 => {
+                    let mut params = vec![Param::new(&self.name())];
+                    match self.next(3) {
+                        0 => params.push(Param{ name: self.name(), default: Some(self.expression(depth - 1)), rest: false }),
+                        1 => params.push(Param{ name: self.name(), default: None, rest: true }),
+                        _ => {},
+                    }
+                    Expression::FnDecl(params, Box::new(self.statement(depth - 1)))
+                },
+                10 => {
+                    let last = self.expression(depth - 1);
+                    let last = if self.next(3) == 0 { Expression::Spread(Box::new(last)) } else { last };
+                    Expression::Call(boxed(self), vec![self.expression(depth - 1), last])
+                },
+                11 => Expression::Array(vec![self.expression(depth - 1)]),
+                12 => Expression::Index(boxed(self), boxed(self)),
+                13 => Expression::Hash(vec![(self.expression(depth - 1), self.expression(depth - 1))]),
+                _ => self.expression(0),
+            }
+        }
+
+        fn statement(&mut self, depth: usize) -> Statement {
+            match self.next(7) {
+                0 => Statement::Let(self.name(), self.expression(depth)),
+                1 => Statement::Ret(self.expression(depth)),
+                2 => self.block(depth),
+                3 => Statement::Throw(self.expression(depth)),
+                4 => {
+                    let handler = if self.next(2) == 0 { Some((self.name(), Box::new(self.block(depth)))) } else { None };
+                    let finally = if handler.is_none() || self.next(2) == 0 { Some(Box::new(self.block(depth))) } else { None };
+                    Statement::Try(Box::new(self.block(depth)), handler, finally)
+                },
+                _ => Statement::ExprStatement(self.expression(depth)),
+            }
+        }
+
+        fn block(&mut self, depth: usize) -> Statement {
+            Statement::BlockStatement((0..self.next(3)).map(|_| self.statement(depth.saturating_sub(1))).collect())
+        }
+    }
+
+    #[test]
+    fn test_round_trip_generated() {
+        let mut generator = Generator(0x2545F4914F6CDD1D);
+        for _ in 0..2000 {
+            let mut program = Program::new();
+            for _ in 0..3 {
+                let statement = generator.statement(4);
+                program.push(statement);
+            }
+            assert_round_trip(&program);
+        }
+    }
+}
@@ -1,9 +1,14 @@
 mod exprs;
+mod format;
 
-use lexer::{ Token, TokenLexer };
+use lexer::{ Token, TokenLexer, Position };
 use ast::*;
 use std::mem;
 use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{ Display, Formatter };
+
+pub use self::format::{ format, format_params };
 
 #[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Debug)]
 enum OpPrecedence {
@@ -21,8 +26,26 @@ pub struct Parser<'a> {
     lexer: &'a mut TokenLexer,
     cur_tok: Token,
     next_tok: Token,
+    cur_pos: Position,
+    next_pos: Position,
+    /// Where the nodes parsed so far start, in the order they were completed
+    positions: Vec<Position>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub message: String,
+    pub pos: Position,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&format!("line {}, column {}: {}", self.pos.line, self.pos.col, self.message))
+    }
 }
 
+type ParseResult<T> = Result<T, ParseError>;
+
 lazy_static! {
     static ref OP_PRECEDENCE: HashMap<Token, OpPrecedence> = {
         let mut opp = HashMap::new();
@@ -43,134 +66,236 @@ lazy_static! {
 impl<'a> Parser<'a> {
     pub fn new(lexer: &mut TokenLexer) -> Parser {
         lexer.init();
-        let cur_tok = lexer.next_token();
-        let next_tok = lexer.next_token();
-        Parser{ lexer, cur_tok, next_tok }
+        let (cur_tok, cur_pos) = lexer.next_spanned();
+        let (next_tok, next_pos) = lexer.next_spanned();
+        Parser{ lexer, cur_tok, next_tok, cur_pos, next_pos, positions: Vec::new() }
+    }
+
+    fn error<T>(&self, message: String) -> ParseResult<T> {
+        Err(ParseError{ message, pos: self.cur_pos })
     }
 
-    fn assert_ident(&mut self) -> String {
+    fn assert_ident(&mut self) -> ParseResult<String> {
         match self.next_token().clone() {
-            Token::Ident(s) => s,
-            _ => panic!("Expected Ident, got {:?}", self.cur_tok),
+            Token::Ident(s) => Ok(s),
+            _ => self.error(format!("Expected Ident, got {:?}", self.cur_tok)),
+        }
+    }
+
+    fn assert_next(&mut self, tok: Token) -> ParseResult<()> {
+        if self.next_token() == &tok {
+            Ok(())
+        } else {
+            self.error(format!("Expected {:?}, got {:?}", tok, self.cur_tok))
         }
     }
 
+    /// Records the start of a node that has just been parsed.
+    fn completed<T>(&mut self, node: T, start: Position) -> T {
+        self.positions.push(start);
+        node
+    }
+
     fn next_token(&mut self) -> &Token {
-        self.cur_tok = mem::replace(&mut self.next_tok, self.lexer.next_token());
+        let (tok, pos) = self.lexer.next_spanned();
+        self.cur_tok = mem::replace(&mut self.next_tok, tok);
+        self.cur_pos = mem::replace(&mut self.next_pos, pos);
         &self.cur_tok
     }
 
-    pub fn parse_program(&mut self) -> Program {
+    pub fn parse_program(&mut self) -> ParseResult<Program> {
         let mut prog = Program::new();
         loop {
             match self.cur_tok {
                 Token::Eof => break,
-                Token::Illegal => panic!("Illegal token {:?}", self.cur_tok),
-                _ => prog.push(self.parse_statement()),
+                Token::Illegal => return self.error(format!("Illegal token {:?}", self.cur_tok)),
+                _ => prog.push(self.parse_statement()?),
             }
             self.next_token();
         }
-        prog
+        prog.set_positions(mem::take(&mut self.positions));
+        Ok(prog)
     }
 
-    fn parse_statement(&mut self) -> Statement {
+    fn parse_statement(&mut self) -> ParseResult<Statement> {
         match self.cur_tok {
             Token::Let => self.parse_let(),
             Token::Ret => self.parse_ret(),
+            Token::Throw => self.parse_throw(),
+            Token::Try => self.parse_try(),
             Token::Lbrace => self.parse_block(),
+            Token::Text(ref text) => {
+                let text = text.clone();
+                Ok(self.completed(Statement::Text(text), self.cur_pos))
+            },
             _ => self.parse_expression_stmt(),
         }
     }
 
-    fn parse_let(&mut self) -> Statement {
-        let ident = self.assert_ident();
-        assert_eq!(self.next_token(), &Token::Assign);
+    fn parse_let(&mut self) -> ParseResult<Statement> {
+        let start = self.cur_pos;
+        let ident = self.assert_ident()?;
+        self.assert_next(Token::Assign)?;
         self.next_token();
-        let rv = Statement::Let(ident.clone(), self.parse_expression(OpPrecedence::Lowest));
+        let rv = Statement::Let(ident.clone(), self.parse_expression(OpPrecedence::Lowest)?);
+        let rv = self.completed(rv, start);
         if self.next_tok == Token::Semicolon {
             self.next_token();
         }
-        rv
+        Ok(rv)
     }
 
-    fn parse_ret(&mut self) -> Statement {
+    fn parse_ret(&mut self) -> ParseResult<Statement> {
+        let start = self.cur_pos;
         self.next_token();
-        let rv = Statement::Ret(self.parse_expression(OpPrecedence::Lowest));
+        let rv = Statement::Ret(self.parse_expression(OpPrecedence::Lowest)?);
+        let rv = self.completed(rv, start);
+        if self.next_tok == Token::Semicolon {
+            self.next_token();
+        }
+        Ok(rv)
+    }
+
+    fn parse_throw(&mut self) -> ParseResult<Statement> {
+        let start = self.cur_pos;
+        self.next_token();
+        let rv = Statement::Throw(self.parse_expression(OpPrecedence::Lowest)?);
+        let rv = self.completed(rv, start);
+        if self.next_tok == Token::Semicolon {
+            self.next_token();
+        }
+        Ok(rv)
+    }
+
+    fn parse_try(&mut self) -> ParseResult<Statement> {
+        let start = self.cur_pos;
+        self.assert_next(Token::Lbrace)?;
+        let body = self.parse_block()?;
+        let handler = if self.next_tok == Token::Catch {
+            self.next_token();
+            self.assert_next(Token::Lparen)?;
+            let name = self.assert_ident()?;
+            self.assert_next(Token::Rparen)?;
+            self.assert_next(Token::Lbrace)?;
+            Some((name, Box::new(self.parse_block()?)))
+        } else {
+            None
+        };
+        let finally = if self.next_tok == Token::Finally {
+            self.next_token();
+            self.assert_next(Token::Lbrace)?;
+            Some(Box::new(self.parse_block()?))
+        } else {
+            None
+        };
+        if handler.is_none() && finally.is_none() {
+            self.next_token();
+            return self.error(format!("Expected Catch or Finally, got {:?}", self.cur_tok));
+        }
+        let rv = self.completed(Statement::Try(Box::new(body), handler, finally), start);
         if self.next_tok == Token::Semicolon {
             self.next_token();
         }
-        rv
+        Ok(rv)
     }
 
-    fn parse_cond(&mut self) -> Expression {
-        assert_eq!(self.next_token(), &Token::Lparen);
+    fn parse_cond(&mut self) -> ParseResult<Expression> {
+        let start = self.cur_pos;
+        self.assert_next(Token::Lparen)?;
         self.next_token();
-        let cond = self.parse_expression(OpPrecedence::Lowest);
-        assert_eq!(self.next_token(), &Token::Rparen);
+        let cond = self.parse_expression(OpPrecedence::Lowest)?;
+        self.assert_next(Token::Rparen)?;
         self.next_token();
-        let if_st = self.parse_statement();
+        let if_st = self.parse_statement()?;
         let else_st = {
             if self.next_tok == Token::Else {
                 self.next_token();
                 self.next_token();
-                self.parse_statement()
+                self.parse_statement()?
             } else {
-                Statement::BlockStatement(Vec::new())
+                let pos = self.cur_pos;
+                self.completed(Statement::BlockStatement(Vec::new()), pos)
             }
         };
-        Expression::If(Box::new(cond), Box::new(if_st), Box::new(else_st))
+        Ok(self.completed(Expression::If(Box::new(cond), Box::new(if_st), Box::new(else_st)), start))
     }
 
-    fn parse_fn(&mut self) -> Expression {
-        let mut params = Vec::new();
-        assert_eq!(self.next_token(), &Token::Lparen);
+    fn parse_fn(&mut self) -> ParseResult<Expression> {
+        let start = self.cur_pos;
+        let mut params: Vec<Param> = Vec::new();
+        self.assert_next(Token::Lparen)?;
         while self.next_tok != Token::Rparen {
-            params.push(self.assert_ident());
+            let rest = self.next_tok == Token::Ellipsis;
+            if rest {
+                self.next_token();
+            }
+            let name = self.assert_ident()?;
+            match params.last() {
+                Some(last) if last.rest => return self.error(format!("{} follows the rest parameter {}", name, last.name)),
+                Some(last) if last.default.is_some() && !rest && self.next_tok != Token::Assign =>
+                    return self.error(format!("{} needs a default value, following {} which has one", name, last.name)),
+                _ => {},
+            }
+            let default = if !rest && self.next_tok == Token::Assign {
+                self.next_token();
+                self.next_token();
+                Some(self.parse_expression(OpPrecedence::Lowest)?)
+            } else {
+                None
+            };
+            params.push(Param{ name, default, rest });
             if self.next_tok == Token::Comma {
                 self.next_token();
             }
         }
         self.next_token();
         self.next_token();
-        Expression::FnDecl(params, Box::new(self.parse_statement()))
+        let body = self.parse_statement()?;
+        Ok(self.completed(Expression::FnDecl(params, Box::new(body)), start))
     }
 
-    fn parse_array(&mut self) -> Expression {
+    fn parse_array(&mut self) -> ParseResult<Expression> {
+        let start = self.cur_pos;
         let mut elems = Vec::new();
         while self.next_tok != Token::Rbracket {
             self.next_token();
-            elems.push(self.parse_expression(OpPrecedence::Lowest));
+            elems.push(self.parse_expression(OpPrecedence::Lowest)?);
             if self.next_tok == Token::Comma {
                 self.next_token();
             }
         }
         self.next_token();
-        Expression::Array(elems)
+        Ok(self.completed(Expression::Array(elems), start))
     }
 
-    fn parse_hash(&mut self) -> Expression {
+    fn parse_hash(&mut self) -> ParseResult<Expression> {
+        let start = self.cur_pos;
         let mut hash = Vec::new();
         while self.next_tok != Token::Rbrace {
             self.next_token();
-            let key = self.parse_expression(OpPrecedence::Lowest);
-            assert_eq!(self.next_token(), &Token::Colon);
+            let key = self.parse_expression(OpPrecedence::Lowest)?;
+            self.assert_next(Token::Colon)?;
             self.next_token();
-            let value = self.parse_expression(OpPrecedence::Lowest);
+            let value = self.parse_expression(OpPrecedence::Lowest)?;
             hash.push((key, value));
             if self.next_tok == Token::Comma {
                 self.next_token();
             }
         }
         self.next_token();
-        Expression::Hash(hash)
+        Ok(self.completed(Expression::Hash(hash), start))
     }
 
-    fn parse_block(&mut self) -> Statement {
+    fn parse_block(&mut self) -> ParseResult<Statement> {
+        let start = self.cur_pos;
         let mut v = Vec::new();
         while self.next_token() != &Token::Rbrace {
-            v.push(self.parse_statement());
+            if self.cur_tok == Token::Eof {
+                return self.error(String::from("Expected Rbrace, got Eof"));
+            }
+            v.push(self.parse_statement()?);
         }
-        Statement::BlockStatement(v)
+        Ok(self.completed(Statement::BlockStatement(v), start))
     }
 
     fn cur_precedence(&self) -> OpPrecedence {
@@ -181,37 +306,42 @@ impl<'a> Parser<'a> {
         OP_PRECEDENCE.get(&self.next_tok).unwrap_or(&OpPrecedence::Lowest).clone()
     }
 
-    fn parse_expression(&mut self, op_prec: OpPrecedence) -> Expression {
+    fn parse_expression(&mut self, op_prec: OpPrecedence) -> ParseResult<Expression> {
+        let start = self.cur_pos;
         let mut left = match self.cur_tok.clone() {
-            Token::Int(i) => Expression::Int(i),
-            Token::Ident(s) => Expression::Ident(s),
-            Token::True => Expression::True,
-            Token::False => Expression::False,
-            Token::Null => Expression::Null,
-            Token::If => self.parse_cond(),
-            Token::Function => self.parse_fn(),
-            Token::Lbracket => self.parse_array(),
-            Token::Lbrace => self.parse_hash(),
-            Token::String(s) => Expression::String(s),
+            Token::Int(i) => self.completed(Expression::Int(i), start),
+            Token::Ident(s) => self.completed(Expression::Ident(s), start),
+            Token::True => self.completed(Expression::True, start),
+            Token::False => self.completed(Expression::False, start),
+            Token::Null => self.completed(Expression::Null, start),
+            Token::If => self.parse_cond()?,
+            Token::Function => self.parse_fn()?,
+            Token::Lbracket => self.parse_array()?,
+            Token::Lbrace => self.parse_hash()?,
+            Token::String(s) => self.completed(Expression::String(s), start),
             Token::Lparen => {
                 self.next_token();
-                let exp = self.parse_expression(OpPrecedence::Lowest);
-                assert_eq!(self.next_token(), &Token::Rparen);
+                let exp = self.parse_expression(OpPrecedence::Lowest)?;
+                self.assert_next(Token::Rparen)?;
                 exp
             },
-            other => exprs::prefix_parser(&other).map(|prefix_fn| {
-                self.next_token();
-                prefix_fn(self.parse_expression(OpPrecedence::Prefix))
-            }).unwrap_or_else(|| panic!("Prefix operator not found: {:?}", &other))
+            other => match exprs::prefix_parser(&other) {
+                Some(prefix_fn) => {
+                    self.next_token();
+                    let operand = self.parse_expression(OpPrecedence::Prefix)?;
+                    self.completed(prefix_fn(operand), start)
+                },
+                None => return self.error(format!("Prefix operator not found: {:?}", &other)),
+            }
         };
 
         while self.next_tok != Token::Semicolon && op_prec < self.peek_precedence() {
             match self.next_tok {
                 Token::Lparen => {
-                    left = self.parse_call(left);
+                    left = self.parse_call(left, start)?;
                 },
                 Token::Lbracket => {
-                    left = self.parse_index(left);
+                    left = self.parse_index(left, start)?;
                 },
                 _ => {
                     let infix = match exprs::infix_parser(&self.next_tok) {
@@ -221,41 +351,52 @@ impl<'a> Parser<'a> {
                     self.next_token();
                     let prec = self.cur_precedence();
                     self.next_token();
-                    left = infix(left, self.parse_expression(prec));
+                    let right = self.parse_expression(prec)?;
+                    left = self.completed(infix(left, right), start);
                 }
             }
         }
-        left
+        Ok(left)
     }
 
-    fn parse_call(&mut self, fn_exp: Expression) -> Expression {
+    fn parse_call(&mut self, fn_exp: Expression, start: Position) -> ParseResult<Expression> {
         let mut params = Vec::new();
         self.next_token();
         while self.next_tok != Token::Rparen {
             self.next_token();
-            params.push(self.parse_expression(OpPrecedence::Lowest));
+            let arg = if self.cur_tok == Token::Ellipsis {
+                let start = self.cur_pos;
+                self.next_token();
+                let spread = Expression::Spread(Box::new(self.parse_expression(OpPrecedence::Lowest)?));
+                self.completed(spread, start)
+            } else {
+                self.parse_expression(OpPrecedence::Lowest)?
+            };
+            params.push(arg);
             if self.next_tok == Token::Comma {
                 self.next_token();
             }
         }
         self.next_token();
-        Expression::Call(Box::new(fn_exp), params)
+        Ok(self.completed(Expression::Call(Box::new(fn_exp), params), start))
     }
 
-    fn parse_index(&mut self, arr_exp: Expression) -> Expression {
+    fn parse_index(&mut self, arr_exp: Expression, start: Position) -> ParseResult<Expression> {
         self.next_token();
         self.next_token();
-        let index = self.parse_expression(OpPrecedence::Lowest);
-        assert_eq!(self.next_token(), &Token::Rbracket);
-        Expression::Index(Box::new(arr_exp), Box::new(index))
+        let index = self.parse_expression(OpPrecedence::Lowest)?;
+        self.assert_next(Token::Rbracket)?;
+        Ok(self.completed(Expression::Index(Box::new(arr_exp), Box::new(index)), start))
     }
 
-    fn parse_expression_stmt(&mut self) -> Statement {
-        let rv = Statement::ExprStatement(self.parse_expression(OpPrecedence::Lowest));
+    fn parse_expression_stmt(&mut self) -> ParseResult<Statement> {
+        let start = self.cur_pos;
+        let rv = Statement::ExprStatement(self.parse_expression(OpPrecedence::Lowest)?);
+        let rv = self.completed(rv, start);
         if self.next_tok == Token::Semicolon {
             self.next_token();
         }
-        rv
+        Ok(rv)
     }
 }
 
@@ -268,7 +409,7 @@ mod test {
     fn test_let() {
         let mut lexer = Lexer::new(String::from("let x = 10;let y=11;"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::Let(String::from("x"), Expression::Int(10))),
             Box::new(Statement::Let(String::from("y"), Expression::Int(11)))
         ]);
@@ -278,7 +419,7 @@ mod test {
     fn test_ret() {
         let mut lexer = Lexer::new(String::from("return x; return 1;"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::Ret(Expression::Ident(String::from("x")))),
             Box::new(Statement::Ret(Expression::Int(1)))
         ]);
@@ -288,7 +429,7 @@ mod test {
     fn test_prefix_stmts() {
         let mut lexer = Lexer::new(String::from("x; 10 ; -1;"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::ExprStatement(
                 Expression::Ident(String::from("x")))),
             Box::new(Statement::ExprStatement(
@@ -302,7 +443,7 @@ mod test {
     fn test_infix_stmts() {
         let mut lexer = Lexer::new(String::from("x + 10;y < z; 1 + 2 * 3 / 4 - 5 == 0; -1-2-3"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::ExprStatement(
                 Expression::Plus(
                     Box::new(Expression::Ident(String::from("x"))),
@@ -342,7 +483,7 @@ mod test {
     fn test_paren() {
         let mut lexer = Lexer::new(String::from("(x * (y + z)) == true"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::ExprStatement(Expression::Eq(Box::new(
                 Expression::Mul(
                     Box::new(Expression::Ident(String::from("x"))),
@@ -359,7 +500,7 @@ mod test {
     fn test_cond() {
         let mut lexer = Lexer::new(String::from("if (x > 0) {let x = 1; x + 1} else (1+2)*3"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::ExprStatement(Expression::If(
                 Box::new(Expression::Gt(
                     Box::new(Expression::Ident(String::from("x"))),
@@ -385,7 +526,7 @@ mod test {
     fn test_only_if() {
         let mut lexer = Lexer::new(String::from("if (((0))) let x = (1);"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::ExprStatement(Expression::If(
                 Box::new(Expression::Int(0)),
                 Box::new(Statement::Let(String::from("x"), Expression::Int(1))),
@@ -398,7 +539,7 @@ mod test {
     fn test_if_precedence() {
         let mut lexer = Lexer::new(String::from("1 == -(if (0) 1)*2"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::ExprStatement(Expression::Eq(
                 Box::new(Expression::Int(1)),
                 Box::new(Expression::Mul(
@@ -419,14 +560,14 @@ mod test {
     fn test_fn_decl() {
         let mut lexer = Lexer::new(String::from("let x = fn() 1; let y = fn(a,b) { let x = 1; a+b }"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::Let(
                 String::from("x"),
                 Expression::FnDecl(Vec::new(), Box::new(Statement::ExprStatement(Expression::Int(1)))))),
             Box::new(Statement::Let(
                 String::from("y"),
                 Expression::FnDecl(
-                    vec![String::from("a"), String::from("b")],
+                    vec![Param::new("a"), Param::new("b")],
                     Box::new(Statement::BlockStatement(vec![
                         Statement::Let(String::from("x"), Expression::Int(1)),
                         Statement::ExprStatement(Expression::Plus(
@@ -436,11 +577,67 @@ mod test {
         ]);
     }
 
+    #[test]
+    fn test_fn_params() {
+        let mut lexer = Lexer::new(String::from("fn(a, b = a + 1, ...rest) rest"));
+        let mut parser = Parser::new(&mut lexer);
+        let params = vec![
+            Param::new("a"),
+            Param{ name: String::from("b"), default: Some(Expression::Plus(
+                Box::new(Expression::Ident(String::from("a"))),
+                Box::new(Expression::Int(1)))), rest: false },
+            Param{ name: String::from("rest"), default: None, rest: true },
+        ];
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
+            Box::new(Statement::ExprStatement(Expression::FnDecl(
+                params, Box::new(Statement::ExprStatement(Expression::Ident(String::from("rest"))))))),
+        ]);
+
+        let error = |code: &str| Parser::new(&mut Lexer::new(String::from(code))).parse_program().unwrap_err().to_string();
+        assert_eq!(error("fn(...a, b) 1"), "line 1, column 10: b follows the rest parameter a");
+        assert_eq!(error("fn(a = 1, b) 1"), "line 1, column 11: b needs a default value, following a which has one");
+    }
+
+    #[test]
+    fn test_spread() {
+        let mut lexer = Lexer::new(String::from("f(1, ...xs + ys)"));
+        let mut parser = Parser::new(&mut lexer);
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
+            Box::new(Statement::ExprStatement(Expression::Call(
+                Box::new(Expression::Ident(String::from("f"))),
+                vec![Expression::Int(1), Expression::Spread(Box::new(Expression::Plus(
+                    Box::new(Expression::Ident(String::from("xs"))),
+                    Box::new(Expression::Ident(String::from("ys"))))))]))),
+        ]);
+    }
+
+    #[test]
+    fn test_try() {
+        let mut lexer = Lexer::new(String::from("try { f() } catch (e) { throw e; } finally { 1 } try { 2 } finally {}"));
+        let mut parser = Parser::new(&mut lexer);
+        let ident = |name: &str| Expression::Ident(String::from(name));
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
+            Box::new(Statement::Try(
+                Box::new(Statement::BlockStatement(vec![
+                    Statement::ExprStatement(Expression::Call(Box::new(ident("f")), vec![]))])),
+                Some((String::from("e"), Box::new(Statement::BlockStatement(vec![Statement::Throw(ident("e"))])))),
+                Some(Box::new(Statement::BlockStatement(vec![Statement::ExprStatement(Expression::Int(1))]))))),
+            Box::new(Statement::Try(
+                Box::new(Statement::BlockStatement(vec![Statement::ExprStatement(Expression::Int(2))])),
+                None,
+                Some(Box::new(Statement::BlockStatement(vec![]))))),
+        ]);
+
+        let error = |code: &str| Parser::new(&mut Lexer::new(String::from(code))).parse_program().unwrap_err().to_string();
+        assert_eq!(error("try { 1 } 2"), "line 1, column 11: Expected Catch or Finally, got Int(2)");
+        assert_eq!(error("try { 1 } catch { 2 }"), "line 1, column 17: Expected Lparen, got Lbrace");
+    }
+
     #[test]
     fn test_fn_call() {
         let mut lexer = Lexer::new(String::from("func(); func1(1); func2(1,2);"));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::ExprStatement(Expression::Call(
                 Box::new(Expression::Ident(String::from("func"))), vec![]))),
             Box::new(Statement::ExprStatement(Expression::Call(
@@ -456,7 +653,7 @@ mod test {
     fn test_str() {
         let mut lexer = Lexer::new(String::from("let x = \"a b \" + \" c d \""));
         let mut parser = Parser::new(&mut lexer);
-        assert_eq!(parser.parse_program().statements(), &vec![
+        assert_eq!(parser.parse_program().unwrap().statements(), &vec![
             Box::new(Statement::Let(
                 String::from("x"),
                 Expression::Plus(
@@ -464,4 +661,101 @@ mod test {
                     Box::new(Expression::String(String::from(" c d ")))))),
         ]);
     }
+
+    #[test]
+    fn test_positions() {
+        let mut lexer = Lexer::new(String::from("let x = 1 +\n  f(y);\nif (x) { -x }"));
+        let program = Parser::new(&mut lexer).parse_program().unwrap();
+        let positions = program.positions();
+        let pos = |line, col| Position{ line, col };
+        let (value, cond) = match (&*program.statements()[0], &*program.statements()[1]) {
+            (Statement::Let(_, value), Statement::ExprStatement(cond)) => (value, cond),
+            other => panic!("unexpected statements {:?}", other),
+        };
+        assert_eq!(positions.statement(&program.statements()[0]), pos(1, 1));
+        assert_eq!(positions.expression(value), pos(1, 9));
+        let (call, arg) = match value {
+            Expression::Plus(_, call) => match &**call {
+                Expression::Call(_, args) => (call, &args[0]),
+                other => panic!("unexpected call {:?}", other),
+            },
+            other => panic!("unexpected value {:?}", other),
+        };
+        assert_eq!(positions.expression(call), pos(2, 3));
+        assert_eq!(positions.expression(arg), pos(2, 5));
+        assert_eq!(positions.statement(&program.statements()[1]), pos(3, 1));
+        match cond {
+            Expression::If(_, consequence, _) => assert_eq!(positions.statement(consequence), pos(3, 8)),
+            other => panic!("unexpected if {:?}", other),
+        }
+        assert_eq!(Program::new().positions().statement(&Statement::BlockStatement(Vec::new())), Position::default());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "no position recorded")]
+    fn test_positions_missing() {
+        let program = Parser::new(&mut Lexer::new(String::from("1"))).parse_program().unwrap();
+        program.positions().statement(&Statement::BlockStatement(Vec::new()));
+    }
+
+    /// Counts the nodes under a statement, checking each has a position of its own.
+    fn positioned_statement(s: &Statement, positions: &Positions) -> usize {
+        assert_ne!(positions.statement(s), Position::default(), "{:?}", s);
+        1 + match s {
+            Statement::Let(_, e) | Statement::Ret(e) | Statement::ExprStatement(e) | Statement::Throw(e) => positioned_expression(e, positions),
+            Statement::BlockStatement(statements) => statements.iter().map(|s| positioned_statement(s, positions)).sum(),
+            Statement::Try(body, handler, finally) => positioned_statement(body, positions)
+                + handler.iter().map(|(_, handler)| positioned_statement(handler, positions)).sum::<usize>()
+                + finally.iter().map(|finally| positioned_statement(finally, positions)).sum::<usize>(),
+            Statement::Text(_) => 0,
+        }
+    }
+
+    fn positioned_expression(e: &Expression, positions: &Positions) -> usize {
+        assert_ne!(positions.expression(e), Position::default(), "{:?}", e);
+        1 + match e {
+            Expression::Plus(l, r) | Expression::Minus(l, r) | Expression::Div(l, r) | Expression::Mul(l, r)
+            | Expression::Eq(l, r) | Expression::Ne(l, r) | Expression::Lt(l, r) | Expression::Gt(l, r)
+            | Expression::Index(l, r) => positioned_expression(l, positions) + positioned_expression(r, positions),
+            Expression::Neg(e) | Expression::Not(e) | Expression::Spread(e) => positioned_expression(e, positions),
+            Expression::If(condition, consequence, alternative) => positioned_expression(condition, positions)
+                + positioned_statement(consequence, positions) + positioned_statement(alternative, positions),
+            Expression::FnDecl(params, body) => params.iter().filter_map(|param| param.default.as_ref())
+                .map(|default| positioned_expression(default, positions)).sum::<usize>() + positioned_statement(body, positions),
+            Expression::Call(function, args) => positioned_expression(function, positions)
+                + args.iter().map(|arg| positioned_expression(arg, positions)).sum::<usize>(),
+            Expression::Array(elems) => elems.iter().map(|e| positioned_expression(e, positions)).sum(),
+            Expression::Hash(pairs) => pairs.iter()
+                .map(|(k, v)| positioned_expression(k, positions) + positioned_expression(v, positions)).sum(),
+            Expression::Int(_) | Expression::Ident(_) | Expression::True | Expression::False
+            | Expression::Null | Expression::String(_) => 0,
+        }
+    }
+
+    #[test]
+    fn test_positions_complete() {
+        let code = "let f = fn(a, b = 1, ...c) { return a + b - c * 2 / 1; };
+            let h = {\"k\": [1, true, false, null][0]};
+            if (!(a == b) != (a < b)) { f(...[1]) } else { -a > 2 };
+            if (a) 1;
+            try { throw \"x\" } catch (e) { e } finally { 1 }";
+        let template = ::server::compile_template("<p><% if (a) { %>text<% } %><%= f(1) %></p>").unwrap();
+        for program in [Parser::new(&mut Lexer::new(String::from(code))).parse_program().unwrap(), template] {
+            let positions = program.positions();
+            let nodes = program.statements().iter().map(|s| positioned_statement(s, &positions)).sum::<usize>();
+            assert_eq!(nodes, positions.len());
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut lexer = Lexer::new(String::from("let x = 1;\nlet = 2;"));
+        let err = Parser::new(&mut lexer).parse_program().unwrap_err();
+        assert_eq!(err.pos, Position{ line: 2, col: 5 });
+        assert_eq!(err.to_string(), "line 2, column 5: Expected Ident, got Assign");
+
+        let mut lexer = Lexer::new(String::from("if (x) {\n  1;\n"));
+        assert!(Parser::new(&mut lexer).parse_program().is_err());
+    }
 }
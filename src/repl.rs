@@ -1,14 +1,238 @@
-use eval::State;
+use eval::{ State, Value };
+use lexer::{ Lexer, tokenize };
+use parser::Parser;
+use rustyline::{ Config, DefaultEditor };
+use rustyline::error::ReadlineError;
+use std::env;
+use std::fs;
 use std::io;
 use std::io::Write;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::time::Instant;
+
+const PROMPT: &str = ">> ";
+/// Prompt for the following lines of an input that has brackets or a string left open.
+const CONTINUATION_PROMPT: &str = ".. ";
+const HISTORY_SIZE: usize = 1000;
 
 pub fn start_repl() {
+    let config = Config::builder().max_history_size(HISTORY_SIZE).unwrap().auto_add_history(false).build();
+    let mut editor = match DefaultEditor::with_config(config) {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("Could not start the REPL: {}", err);
+            return;
+        },
+    };
+    let history = history_path();
+    if let Some(ref path) = history {
+        // There is no history yet the first time round
+        let _ = editor.load_history(path);
+    }
+
     let mut state = State::new();
+    let mut input = String::new();
     loop {
-        let mut input = String::new();
-        print!(">> ");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut input).unwrap();
-        state.eval(&input, &mut io::stdout()).map(|out| println!("-> {}\n", out));
+        let prompt = if input.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                input.push_str(&line);
+                input.push('\n');
+                if is_incomplete(&input) {
+                    continue;
+                }
+                let entry = input.trim_end();
+                if !entry.trim().is_empty() {
+                    let _ = editor.add_history_entry(entry);
+                    let quit = if entry.trim_start().starts_with(':') {
+                        command(&mut state, entry.trim(), &mut io::stdout()) == Command::Quit
+                    } else {
+                        eval(&mut state, entry, &mut io::stdout());
+                        false
+                    };
+                    if quit {
+                        break;
+                    }
+                }
+                input.clear();
+            },
+            // Ctrl-C drops what has been typed so far, Ctrl-D leaves
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("Could not read input: {}", err);
+                break;
+            },
+        }
+    }
+    if let Some(ref path) = history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("Could not save history to {}: {}", path.display(), err);
+        }
+    }
+}
+
+const HELP: &str = "\
+Enter an expression or statements to evaluate them. Lines are joined while brackets or strings are left open.
+Commands:
+  :help          Show this help
+  :quit          Leave the REPL, as does Ctrl-D
+  :env           List the bindings made so far
+  :type <expr>   Show the type of an expression, leaving bindings as they are
+  :ast <code>    Show how code is parsed
+  :tokens <code> Show the tokens code is made of
+  :load <file>   Evaluate a file, keeping its bindings
+  :reset         Forget all bindings
+  :time <code>   Evaluate code and show how long it took";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Continue,
+    Quit,
+}
+
+/// Runs a `:command`, writing what it shows to `out`.
+fn command(state: &mut State, input: &str, out: &mut dyn Write) -> Command {
+    let (name, arg) = match input.find(char::is_whitespace) {
+        Some(i) => (&input[..i], input[i..].trim()),
+        None => (input, ""),
+    };
+    let needs_arg = |out: &mut dyn Write| {
+        let _ = writeln!(out, "{} needs an argument, see :help", name);
+    };
+    match name {
+        ":quit" | ":q" => return Command::Quit,
+        ":help" | ":h" => {
+            let _ = writeln!(out, "{}", HELP);
+        },
+        ":env" => for (name, value) in state.bindings() {
+            let _ = writeln!(out, "{} = {}", name, value);
+        },
+        ":type" | ":ast" | ":tokens" | ":load" | ":time" if arg.is_empty() => needs_arg(out),
+        // Evaluated in a copy of the bindings and without output, so looking only has no effect
+        ":type" => match catch_panic(|| state.clone().eval(arg, &mut io::sink())) {
+            Some(Some(Value::Error(err))) => {
+                let _ = writeln!(out, "-> {}", err);
+            },
+            Some(Some(value)) => {
+                let _ = writeln!(out, "{}", value.type_name());
+            },
+            Some(None) => {
+                let _ = writeln!(out, "{} doesn't evaluate to a value", arg);
+            },
+            None => {},
+        },
+        ":ast" => match Parser::new(&mut Lexer::new(String::from(arg))).parse_program() {
+            Ok(program) => for statement in program.statements() {
+                let _ = writeln!(out, "{:#?}", statement);
+            },
+            Err(err) => {
+                let _ = writeln!(out, "-> Syntax: {}", err);
+            },
+        },
+        ":tokens" => for (token, pos) in tokenize(arg) {
+            let _ = writeln!(out, "{}:{}\t{:?}", pos.line, pos.col, token);
+        },
+        ":load" => match fs::read_to_string(arg) {
+            Ok(code) => eval(state, &code, out),
+            Err(err) => {
+                let _ = writeln!(out, "Could not read {}: {}", arg, err);
+            },
+        },
+        ":reset" => *state = State::new(),
+        ":time" => {
+            let started = Instant::now();
+            eval(state, arg, out);
+            let _ = writeln!(out, "Took {:?}", started.elapsed());
+        },
+        _ => {
+            let _ = writeln!(out, "Unknown command {}, see :help", name);
+        },
+    }
+    Command::Continue
+}
+
+/// Evaluates an input, keeping the session going even if the interpreter panics on it.
+fn eval(state: &mut State, input: &str, out: &mut dyn Write) {
+    if let Some(Some(value)) = catch_panic(|| state.eval(input, out)) {
+        let _ = writeln!(out, "-> {}\n", value);
+    }
+}
+
+/// Runs `f`, returning `None` if it panicked. The panic message has been printed already by then.
+fn catch_panic<T, F: FnOnce() -> T>(f: F) -> Option<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).ok()
+}
+
+/// History is kept in `~/.monkeylang_history`, or the file `MONKEYLANG_HISTORY` names.
+fn history_path() -> Option<PathBuf> {
+    env::var_os("MONKEYLANG_HISTORY").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".monkeylang_history")))
+}
+
+/// Whether the input has brackets or a string left open, so more lines are needed to complete it.
+fn is_incomplete(input: &str) -> bool {
+    let mut depth = 0i32;
+    let mut in_string = false;
+    for c in input.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if in_string => {},
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ => {},
+        }
+    }
+    // Too many closing brackets can't be fixed by more input; that's for the parser to report
+    in_string || depth > 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ is_incomplete, command, Command };
+    use eval::State;
+
+    fn run(state: &mut State, input: &str) -> String {
+        let mut out = Vec::new();
+        assert_eq!(command(state, input, &mut out), Command::Continue);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut state = State::new();
+        state.eval("let xs = [1, 2]; let add = fn(a, b) { a + b };", &mut Vec::new());
+        assert_eq!(run(&mut state, ":env"), "add = fn(a, b)\nxs = [1, 2]\n");
+        assert_eq!(run(&mut state, ":type xs"), "array\n");
+        assert_eq!(run(&mut state, ":type  add(1, 2)"), "int\n");
+        assert_eq!(run(&mut state, ":ast -x"), "ExprStatement(\n    Neg(\n        Ident(\n            \"x\",\n        ),\n    ),\n)\n");
+        assert_eq!(run(&mut state, ":tokens let x\n= 1;"), "1:1\tLet\n1:5\tIdent(\"x\")\n2:1\tAssign\n2:3\tInt(1)\n2:4\tSemicolon\n");
+        assert!(run(&mut state, ":time add(2, 3)").starts_with("-> 5\n\nTook "));
+        assert_eq!(run(&mut state, ":type"), ":type needs an argument, see :help\n");
+        assert_eq!(run(&mut state, ":frobnicate"), "Unknown command :frobnicate, see :help\n");
+        assert!(run(&mut state, ":load /nonexistent.ml").starts_with("Could not read /nonexistent.ml"));
+        assert_eq!(run(&mut state, ":reset"), "");
+        assert_eq!(run(&mut state, ":env"), "");
+        assert_eq!(command(&mut state, ":quit", &mut Vec::new()), Command::Quit);
+    }
+
+    #[test]
+    fn test_type_has_no_effect() {
+        let mut state = State::new();
+        state.eval("let xs = [1, 2];", &mut Vec::new());
+        assert_eq!(run(&mut state, ":type let xs = 3; let ys = print(xs); xs"), "int\n");
+        assert_eq!(run(&mut state, ":env"), "xs = [1, 2]\n");
+    }
+
+    #[test]
+    fn test_is_incomplete() {
+        assert!(is_incomplete("let add = fn(a, b) {\n"));
+        assert!(is_incomplete("let xs = [1,\n2"));
+        assert!(is_incomplete("puts(\"a (\n"));
+        assert!(!is_incomplete("let add = fn(a, b) {\n a + b\n};\n"));
+        assert!(!is_incomplete("puts(\"{\")"));
+        assert!(!is_incomplete("1 + 2)"));
     }
 }
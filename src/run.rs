@@ -0,0 +1,50 @@
+use eval::{ State, Value, RuntimeError };
+use std::io::Write;
+
+/// Names bound for programs besides the builtins.
+pub const GLOBALS: &[&str] = &["args"];
+
+/// Runs a program, with what it prints written to `out` and `args` bound to the given arguments.
+/// A first line starting with `#!` is skipped, so scripts can be made executable.
+pub fn run(code: &str, args: &[String], out: &mut dyn Write) -> Result<(), RuntimeError> {
+    let code = strip_shebang(code);
+    let mut state = State::new();
+    state.set(&String::from("args"), Value::Array(args.iter().cloned().map(Value::Str).collect()));
+    match state.eval(code, out) {
+        Some(Value::Error(err)) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Leaves out a first line starting with `#!`.
+pub fn strip_shebang(code: &str) -> &str {
+    match code.strip_prefix("#!") {
+        // Keep the line break so positions still match the file
+        Some(rest) => rest.find('\n').map_or("", |i| &rest[i..]),
+        None => code,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::run;
+    use eval::ErrorKind;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| String::from(*a)).collect()
+    }
+
+    #[test]
+    fn test_run() {
+        let mut out = Vec::new();
+        let code = "#!/usr/bin/env monkeylang run\nprintln(len(args));\nmap(args, fn(a) { print(a + \",\") });\n";
+        assert_eq!(run(code, &args(&["a", "b c"]), &mut out), Ok(()));
+        assert_eq!(String::from_utf8(out).unwrap(), "2\na,b c,");
+
+        let err = run("#!/bin/monkeylang\nlet x = ;", &[], &mut Vec::new()).unwrap_err();
+        assert_eq!((err.kind, err.message.starts_with("line 2, column 9")), (ErrorKind::Syntax, true));
+        assert_eq!(run("print(1);\njson_decode(\"[\")", &[], &mut Vec::new()).unwrap_err().kind, ErrorKind::Json);
+        assert_eq!(run("print(1 / 0)", &[], &mut Vec::new()).unwrap_err().to_string(), "Arithmetic: division by zero");
+        assert_eq!(run("-(-2147483647 - 1)", &[], &mut Vec::new()).unwrap_err().to_string(), "Arithmetic: integer overflow");
+    }
+}
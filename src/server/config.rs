@@ -0,0 +1,351 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+use eval::Limits;
+use super::log::{ LogLevel, LogFormat, LogTarget };
+
+/// What the server does with connections while all workers are busy and the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Stop accepting connections until a worker frees up room, leaving them to the OS backlog
+    Block,
+    /// Answer them at once with `503 SERVICE UNAVAILABLE`
+    Reject,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerConfig {
+    pub interface: String,
+    pub port: u16,
+    /// Directory that files are served from; nothing outside of it is reachable.
+    pub root: PathBuf,
+    /// Files looked up, in order, when a directory is requested.
+    pub index_files: Vec<String>,
+    /// Whether directories without an index file are listed, rather than not found.
+    pub list_directories: bool,
+    /// Whether `/_health` and `/_metrics` are answered by the server itself.
+    pub status_endpoints: bool,
+    /// Routes file mapping methods and paths onto scripts; requests no route matches are served from `root`.
+    pub routes: Option<PathBuf>,
+    /// PEM files with the certificate chain and private key to serve HTTPS with, if built with the `tls` feature.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub threads: usize,
+    /// How many connections may wait for a free worker.
+    pub queue_size: usize,
+    /// What happens to connections arriving while the queue is full.
+    pub overflow: Overflow,
+    /// Maximum size of the request line and headers.
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    /// How long in-flight requests are given to complete once the server is asked to stop.
+    pub shutdown_timeout: Duration,
+    /// Limits applied to every script evaluated by the server, so a single request can't tie up a worker.
+    pub limits: Limits,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// Where the access log is written, one line per request.
+    pub access_log: LogTarget,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            interface: String::from("localhost"),
+            port: 80,
+            root: PathBuf::from("public"),
+            index_files: vec![String::from("index.ml")],
+            list_directories: false,
+            status_endpoints: true,
+            routes: None,
+            tls_cert: None,
+            tls_key: None,
+            threads: 8,
+            queue_size: 128,
+            overflow: Overflow::Block,
+            max_header_size: 40960,
+            max_body_size: 1 << 20,
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            shutdown_timeout: Duration::from_secs(30),
+            limits: Limits {
+                max_steps: Some(10_000_000),
+                max_time: Some(Duration::from_secs(10)),
+                max_depth: Some(1000),
+                max_size: Some(1 << 20),
+            },
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Common,
+            access_log: LogTarget::Stdout,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds a config from command line arguments: an optional interface and port followed by
+    /// `--option value` pairs. Options from a `--config` file are applied first, so flags override them.
+    pub fn from_args(args: &[String]) -> Result<ServerConfig, String> {
+        let mut config = ServerConfig::default();
+        let mut flags = Vec::new();
+        let mut positional = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                let value = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+                if name == "config" {
+                    config.load_file(value)?;
+                } else {
+                    flags.push((name.replace('-', "_"), value));
+                }
+            } else {
+                positional.push(arg);
+            }
+        }
+        if positional.len() > 2 {
+            return Err(format!("Unexpected argument {}", positional[2]));
+        }
+        if let Some(interface) = positional.first() {
+            config.set("interface", interface)?;
+        }
+        if let Some(port) = positional.get(1) {
+            config.set("port", port)?;
+        }
+        for (key, value) in flags {
+            config.set(&key, value)?;
+        }
+        Ok(config)
+    }
+
+    /// Applies a config file made of `key = value` lines. Values may be quoted,
+    /// lists are written as `["a", "b"]` and `#` at the start of a line or after a space starts a comment.
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("Could not read config file {}: {}", path, e))?;
+        self.load_str(&contents).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn load_str(&mut self, contents: &str) -> Result<(), String> {
+        for (i, line) in contents.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut kv = line.splitn(2, '=');
+            let key = kv.next().unwrap().trim();
+            let value = kv.next().ok_or_else(|| format!("line {}: expected key = value", i + 1))?.trim();
+            self.set(key, value).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Sets a single option by the name used in config files.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = unquote(value);
+        match key {
+            "interface" => self.interface = String::from(value),
+            "port" => self.port = parse_num(key, value)?,
+            "root" => self.root = PathBuf::from(value),
+            "index" => {
+                let list = value.trim_start_matches('[').trim_end_matches(']');
+                self.index_files = list.split(',').map(|f| String::from(unquote(f.trim()))).filter(|f| !f.is_empty()).collect();
+            },
+            "list_directories" => self.list_directories = parse_bool(key, value)?,
+            "status_endpoints" => self.status_endpoints = parse_bool(key, value)?,
+            "routes" => self.routes = parse_optional(key, value, |_, path| Ok(PathBuf::from(path)))?,
+            "tls_cert" => self.tls_cert = parse_optional(key, value, |_, path| Ok(PathBuf::from(path)))?,
+            "tls_key" => self.tls_key = parse_optional(key, value, |_, path| Ok(PathBuf::from(path)))?,
+            "threads" => {
+                self.threads = parse_num(key, value)?;
+                if self.threads == 0 {
+                    return Err(String::from("threads must be at least 1"));
+                }
+            },
+            "queue_size" => {
+                self.queue_size = parse_num(key, value)?;
+                if self.queue_size == 0 {
+                    return Err(String::from("queue_size must be at least 1"));
+                }
+            },
+            "overflow" => self.overflow = match value {
+                "block" => Overflow::Block,
+                "reject" => Overflow::Reject,
+                _ => return Err(format!("Invalid overflow {}, expected block or reject", value)),
+            },
+            "max_header_size" => self.max_header_size = parse_num(key, value)?,
+            "max_body_size" => self.max_body_size = parse_num(key, value)?,
+            "read_timeout" => self.read_timeout = parse_optional(key, value, parse_duration)?,
+            "write_timeout" => self.write_timeout = parse_optional(key, value, parse_duration)?,
+            "shutdown_timeout" => self.shutdown_timeout = parse_duration(key, value)?,
+            "max_steps" => self.limits.max_steps = parse_optional(key, value, parse_num)?,
+            "max_time" => self.limits.max_time = parse_optional(key, value, parse_duration)?,
+            "max_depth" => self.limits.max_depth = parse_optional(key, value, parse_num)?,
+            "max_size" => self.limits.max_size = parse_optional(key, value, parse_num)?,
+            "log_level" => self.log_level = match value {
+                "off" => LogLevel::Off,
+                "error" => LogLevel::Error,
+                "info" => LogLevel::Info,
+                "debug" => LogLevel::Debug,
+                _ => return Err(format!("Invalid log_level {}, expected off, error, info or debug", value)),
+            },
+            "log_format" => self.log_format = match value {
+                "common" => LogFormat::Common,
+                "combined" => LogFormat::Combined,
+                "json" => LogFormat::Json,
+                _ => return Err(format!("Invalid log_format {}, expected common, combined or json", value)),
+            },
+            "access_log" => self.access_log = match value {
+                "off" => LogTarget::Off,
+                "stdout" => LogTarget::Stdout,
+                "stderr" => LogTarget::Stderr,
+                path => LogTarget::File(PathBuf::from(path)),
+            },
+            _ => return Err(format!("Unknown option {}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Cuts a line at the `#` starting a comment, which must begin the line or follow whitespace
+/// outside quotes, so that values such as `"/srv/a#b"` are kept whole.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut after_space = true;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted && after_space => return &line[..i],
+            _ => {},
+        }
+        after_space = c.is_whitespace();
+    }
+    line
+}
+
+fn unquote(value: &str) -> &str {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+fn parse_num<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid number {} for {}", value, key))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("Invalid {} {}, expected true or false", key, value)),
+    }
+}
+
+/// Parses durations such as `500ms`, `10s` or `2m`; a bare number is taken as seconds.
+fn parse_duration(key: &str, value: &str) -> Result<Duration, String> {
+    let (num, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let num: u64 = parse_num(key, num)?;
+    let duration = match unit {
+        "ms" => Some(Duration::from_millis(num)),
+        "s" => Some(Duration::from_secs(num)),
+        "m" => num.checked_mul(60).map(Duration::from_secs),
+        _ => None,
+    };
+    duration.ok_or_else(|| format!("Invalid duration {} for {}, expected a number followed by ms, s or m", value, key))
+}
+
+/// Parses an option that can be disabled with `none`.
+fn parse_optional<T>(key: &str, value: &str, parse: fn(&str, &str) -> Result<T, String>) -> Result<Option<T>, String> {
+    if value == "none" {
+        Ok(None)
+    } else {
+        parse(key, value).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ ServerConfig, Overflow };
+    use server::log::{ LogLevel, LogFormat, LogTarget };
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| String::from(*a)).collect()
+    }
+
+    #[test]
+    fn test_load_str() {
+        let mut config = ServerConfig::default();
+        config.load_str("# Production settings
+            root = \"/srv/www\"
+            index = [\"index.ml\", \"index.html\"]
+            threads = 16
+            queue_size = 64
+            overflow = reject
+            read_timeout = 500ms   # slow clients get dropped
+            max_steps = none
+            list_directories = true
+            status_endpoints = false
+            routes = routes.conf
+            tls_cert = /etc/monkeylang/cert.pem
+            log_level = error
+            log_format = json
+            access_log = /var/log/monkeylang/access.log
+        ").unwrap();
+        assert_eq!(config.root, PathBuf::from("/srv/www"));
+        assert_eq!(config.index_files, vec![String::from("index.ml"), String::from("index.html")]);
+        assert_eq!(config.threads, 16);
+        assert_eq!((config.queue_size, config.overflow), (64, Overflow::Reject));
+        assert_eq!(config.read_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(config.limits.max_steps, None);
+        assert!(config.list_directories);
+        assert!(!config.status_endpoints);
+        assert_eq!(config.routes, Some(PathBuf::from("routes.conf")));
+        assert_eq!(config.tls_cert, Some(PathBuf::from("/etc/monkeylang/cert.pem")));
+        assert_eq!(config.log_level, LogLevel::Error);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.access_log, LogTarget::File(PathBuf::from("/var/log/monkeylang/access.log")));
+    }
+
+    #[test]
+    fn test_load_str_hash_in_value() {
+        let mut config = ServerConfig::default();
+        config.load_str("root = \"/srv/a#b\" # quoted
+            index = [\"#index.ml\"]
+            access_log = logs/access#1.log
+        ").unwrap();
+        assert_eq!(config.root, PathBuf::from("/srv/a#b"));
+        assert_eq!(config.index_files, vec![String::from("#index.ml")]);
+        assert_eq!(config.access_log, LogTarget::File(PathBuf::from("logs/access#1.log")));
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = ServerConfig::from_args(&args(&["0.0.0.0", "8080", "--threads", "2", "--max-time", "2s"])).unwrap();
+        assert_eq!(config.interface, "0.0.0.0");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.threads, 2);
+        assert_eq!(config.limits.max_time, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(ServerConfig::from_args(&args(&["localhost", "http"])), Err(String::from("Invalid number http for port")));
+        assert_eq!(ServerConfig::from_args(&args(&["--threads"])), Err(String::from("Missing value for --threads")));
+        assert_eq!(ServerConfig::from_args(&args(&["--colour", "blue"])), Err(String::from("Unknown option colour")));
+        assert!(ServerConfig::default().load_str("threads 4").is_err());
+        assert!(ServerConfig::default().set("read_timeout", "10h").is_err());
+        assert!(ServerConfig::default().set("shutdown_timeout", "999999999999999999m").is_err());
+        assert!(ServerConfig::default().set("list_directories", "yes").is_err());
+        assert!(ServerConfig::default().set("queue_size", "0").is_err());
+        assert!(ServerConfig::default().set("overflow", "drop").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::convert::TryFrom;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+/// Calendar representation of a point in time, in UTC.
+#[derive(Debug, PartialEq)]
+pub struct UtcTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millis: u32,
+}
+
+impl UtcTime {
+    pub fn from(time: SystemTime) -> UtcTime {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs() as i64;
+        let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+        let secs_of_day = secs.rem_euclid(86400) as u32;
+        UtcTime {
+            year, month, day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+
+    /// Formats as used in Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`.
+    pub fn clf(&self) -> String {
+        format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            self.day, MONTHS[self.month as usize - 1], self.year, self.hour, self.minute, self.second)
+    }
+
+    /// Formats as ISO 8601 with millisecond precision, e.g. `2000-10-10T13:55:36.000Z`.
+    pub fn iso8601(&self) -> String {
+        format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis)
+    }
+
+    /// Formats as used in HTTP headers, e.g. `Tue, 10 Oct 2000 13:55:36 GMT`.
+    pub fn http_date(&self) -> String {
+        let days = days_from_civil(self.year, self.month, self.day);
+        format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT", WEEKDAYS[days.rem_euclid(7) as usize],
+            self.day, MONTHS[self.month as usize - 1], self.year, self.hour, self.minute, self.second)
+    }
+}
+
+/// Parses a date in the format used in HTTP headers, as produced by `UtcTime::http_date`.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let parts = date.split_whitespace().collect::<Vec<&str>>();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+    let day: u32 = parts[1].parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == parts[2])? as u32 + 1;
+    let year: i64 = parts[3].parse().ok()?;
+    let time = parts[4].split(':').map(|n| n.parse::<u64>().ok()).collect::<Option<Vec<u64>>>()?;
+    if time.len() != 3 || time[0] > 23 || time[1] > 59 || time[2] > 60 || day == 0 || day > 31 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + time[0] * 3600 + time[1] * 60 + time[2]))
+}
+
+/// Converts a date in the proleptic Gregorian calendar to days since the unix epoch; the inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Converts days since the unix epoch to a (year, month, day) date in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Shift the epoch to 0000-03-01 so that leap days fall at the end of each 400 year era
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ UtcTime, parse_http_date };
+    use std::time::{ Duration, UNIX_EPOCH };
+
+    #[test]
+    fn test_utc_time() {
+        let time = UtcTime::from(UNIX_EPOCH + Duration::from_millis(971_186_136_042));
+        assert_eq!(time.clf(), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(time.iso8601(), "2000-10-10T13:55:36.042Z");
+        assert_eq!(UtcTime::from(UNIX_EPOCH).iso8601(), "1970-01-01T00:00:00.000Z");
+        assert_eq!(UtcTime::from(UNIX_EPOCH + Duration::from_secs(951_782_400)).clf(), "29/Feb/2000:00:00:00 +0000");
+        assert_eq!(time.http_date(), "Tue, 10 Oct 2000 13:55:36 GMT");
+        assert_eq!(UtcTime::from(UNIX_EPOCH).http_date(), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        for secs in [0, 951_782_400, 971_186_136, 1_700_000_000] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse_http_date(&UtcTime::from(time).http_date()), Some(time));
+        }
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+    }
+}
@@ -0,0 +1,233 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use escape;
+use super::date::{ UtcTime, parse_http_date };
+use super::http::{ Request, Response, Body };
+
+/// Guesses the media type of a file from its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "wasm" => "application/wasm",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serves a file as it is, answering conditional requests with `304 NOT MODIFIED`
+/// and `Range` requests with the part of the file asked for.
+pub fn serve(path: &Path, request: &Request) -> io::Result<Response> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let len = metadata.len();
+    let modified = metadata.modified()?;
+    let mtime = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", len, mtime.as_nanos());
+    let last_modified = UtcTime::from(modified).http_date();
+    let response = |status, body| Response{ status, headers: Vec::new(), body }
+        .header("ETag", &etag)
+        .header("Last-Modified", &last_modified)
+        .header("Accept-Ranges", "bytes");
+
+    let not_modified = match request.header("If-None-Match") {
+        Some(tags) => tags.split(',').map(|tag| tag.trim()).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag),
+        // Dates in headers only have a precision of seconds
+        None => request.header("If-Modified-Since").and_then(parse_http_date)
+            .is_some_and(|since| mtime.as_secs() <= since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+    };
+    if not_modified && request.method == "GET" {
+        return Ok(response("304 NOT MODIFIED", Body::Bytes(Vec::new())));
+    }
+
+    // A range is only served if the client still has the same version of the file it got the rest from
+    let range = request.header("Range")
+        .filter(|_| request.header("If-Range").is_none_or(|v| v == etag || v == last_modified))
+        .and_then(|range| parse_range(range, len));
+    Ok(match range {
+        Some(Ok((start, end))) => response("206 PARTIAL CONTENT", Body::File{ file, start, len: end - start + 1 })
+            .header("Content-Range", &format!("bytes {}-{}/{}", start, end, len))
+            .header("Content-Type", content_type(path)),
+        Some(Err(())) => response("416 RANGE NOT SATISFIABLE", Body::Bytes(Vec::new()))
+            .header("Content-Range", &format!("bytes */{}", len)),
+        None => response("200 OK", Body::File{ file, start: 0, len })
+            .header("Content-Type", content_type(path)),
+    })
+}
+
+/// Parses a `Range` header into the first and last byte it asks for. Returns `None` for ranges that
+/// should be ignored, such as malformed ones or several ranges at once, and an error for ranges
+/// outside of the file.
+fn parse_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(suffix), len - 1)
+        },
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        },
+    };
+    if start >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+/// Lists the files in a directory as an HTML page. `url_path` is the path the directory was
+/// requested at, decoded.
+pub fn list_directory(dir: &Path, url_path: &str) -> io::Result<Response> {
+    let mut entries = fs::read_dir(dir)?.filter_map(|entry| {
+        let entry = entry.ok()?;
+        let name = entry.file_name().into_string().ok()?;
+        if name.starts_with('.') {
+            return None;
+        }
+        Some((name, entry.file_type().ok()?.is_dir()))
+    }).collect::<Vec<(String, bool)>>();
+    entries.sort();
+
+    let base = url_path.trim_matches('/');
+    let title = escape::html(&format!("/{}", base));
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+        <body>\n<h1>Index of {0}</h1>\n<ul>\n", title);
+    let encoded = base.split('/').map(escape::url).collect::<Vec<String>>().join("/");
+    let prefix = if base.is_empty() { String::from("/") } else { format!("/{}/", encoded) };
+    if !base.is_empty() {
+        let parent = encoded.rsplit_once('/').map_or("", |(parent, _)| parent);
+        html.push_str(&format!("<li><a href=\"/{}\">../</a></li>\n", escape::html(parent)));
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        html.push_str(&format!("<li><a href=\"{}{}{}\">{}{}</a></li>\n",
+            escape::html(&prefix), escape::url(&name), slash, escape::html(&name), slash));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    Ok(Response::new("200 OK", html.into_bytes()).header("Content-Type", "text/html; charset=utf-8"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ serve, parse_range, list_directory };
+    use server::http::{ Request, Response };
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: String::from("GET"),
+            target: String::from("/file.bin"),
+            version: String::from("HTTP/1.1"),
+            headers: headers.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect(),
+            body: Vec::new(),
+        }
+    }
+
+    fn written(mut response: Response) -> Vec<u8> {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        out
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> &'a str {
+        response.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str()).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("monkeylang-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=500-2000", 1000), Some(Ok((500, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=9-1", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = temp_dir("files");
+        let path = dir.join("file.bin");
+        let contents = (0..=255).collect::<Vec<u8>>();
+        fs::write(&path, &contents).unwrap();
+
+        let full = serve(&path, &request(&[])).unwrap();
+        assert_eq!(full.status, "200 OK");
+        assert_eq!(header(&full, "Content-Type"), "application/octet-stream");
+        let etag = String::from(header(&full, "ETag"));
+        let last_modified = String::from(header(&full, "Last-Modified"));
+        assert!(written(full).ends_with(&contents));
+
+        assert_eq!(serve(&path, &request(&[("If-None-Match", &etag)])).unwrap().status, "304 NOT MODIFIED");
+        assert_eq!(serve(&path, &request(&[("If-None-Match", "\"other\"")])).unwrap().status, "200 OK");
+        assert_eq!(serve(&path, &request(&[("If-Modified-Since", &last_modified)])).unwrap().status, "304 NOT MODIFIED");
+        let not_modified = written(serve(&path, &request(&[("If-None-Match", &etag)])).unwrap());
+        assert!(!String::from_utf8(not_modified).unwrap().contains("Content-Length"));
+
+        let partial = serve(&path, &request(&[("Range", "bytes=16-31")])).unwrap();
+        assert_eq!(partial.status, "206 PARTIAL CONTENT");
+        assert_eq!(header(&partial, "Content-Range"), "bytes 16-31/256");
+        assert!(written(partial).ends_with(&contents[16..32]));
+        let stale = serve(&path, &request(&[("Range", "bytes=16-31"), ("If-Range", "\"other\"")])).unwrap();
+        assert_eq!(stale.status, "200 OK");
+        let unsatisfiable = serve(&path, &request(&[("Range", "bytes=300-")])).unwrap();
+        assert_eq!(unsatisfiable.status, "416 RANGE NOT SATISFIABLE");
+        assert_eq!(header(&unsatisfiable, "Content-Range"), "bytes */256");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_directory() {
+        let dir = temp_dir("listing");
+        fs::create_dir_all(dir.join("sub dir")).unwrap();
+        fs::write(dir.join("<b>.txt"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+        let listing = String::from_utf8(written(list_directory(&dir, "/files/").unwrap())).unwrap();
+        assert!(listing.contains("<li><a href=\"/\">../</a></li>"));
+        assert!(listing.contains("<li><a href=\"/files/%3Cb%3E.txt\">&lt;b&gt;.txt</a></li>"));
+        assert!(listing.contains("<li><a href=\"/files/sub%20dir/\">sub dir/</a></li>"));
+        assert!(!listing.contains("hidden"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io;
+use std::io::{ Read, Write, Seek, SeekFrom };
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads a request whose head fits in `max_header` bytes and body in `max_body` bytes.
+    /// Returns `Ok(None)` if the client closed the connection without sending anything,
+    /// and the status line to respond with if the request can't be read.
+    pub fn read<R: Read>(stream: &mut R, max_header: usize, max_body: usize) -> Result<Option<Request>, &'static str> {
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+        let (head_len, body_start) = loop {
+            if let Some(end) = find_head_end(&buf) {
+                break end;
+            }
+            if buf.len() > max_header {
+                return Err("431 REQUEST HEADER FIELDS TOO LARGE");
+            }
+            match stream.read(&mut chunk).map_err(read_error)? {
+                0 if buf.is_empty() => return Ok(None),
+                0 => break (buf.len(), buf.len()),
+                n => buf.extend_from_slice(&chunk[..n]),
+            }
+        };
+        if head_len > max_header {
+            return Err("431 REQUEST HEADER FIELDS TOO LARGE");
+        }
+
+        let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+        let mut lines = head.lines();
+        let mut parts = lines.next().unwrap_or("").split(' ');
+        let method = String::from(parts.next().unwrap_or(""));
+        let target = String::from(parts.next().ok_or("400 BAD REQUEST")?);
+        let version = String::from(parts.next().unwrap_or("HTTP/1.0"));
+        let headers = lines.filter_map(|line| {
+            let mut kv = line.splitn(2, ':');
+            let name = kv.next()?.trim();
+            let value = kv.next()?.trim();
+            Some((String::from(name), String::from(value)))
+        }).collect::<Vec<(String, String)>>();
+
+        let mut body = buf.split_off(body_start);
+        let mut request = Request{ method, target, version, headers, body: Vec::new() };
+        let content_length = match request.header("Content-Length") {
+            Some(len) => len.parse::<usize>().map_err(|_| "400 BAD REQUEST")?,
+            None => body.len(),
+        };
+        if content_length > max_body {
+            return Err("413 PAYLOAD TOO LARGE");
+        }
+        while body.len() < content_length {
+            match stream.read(&mut chunk).map_err(read_error)? {
+                0 => break,
+                n => body.extend_from_slice(&chunk[..n]),
+            }
+        }
+        body.truncate(content_length);
+        request.body = body;
+        Ok(Some(request))
+    }
+
+    /// Looks up a header by its case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// `len` bytes of a file from `start` on, copied to the connection as they are read
+    File{ file: File, start: u64, len: u64 },
+}
+
+impl Body {
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File{ len, .. } => *len,
+        }
+    }
+}
+
+/// Response to a request, written back once it is complete.
+#[derive(Debug)]
+pub struct Response {
+    pub status: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: &'static str, body: Vec<u8>) -> Response {
+        Response{ status, headers: Vec::new(), body: Body::Bytes(body) }
+    }
+
+    /// A plain text response, as used for errors.
+    pub fn text(status: &'static str, body: &str) -> Response {
+        Response::new(status, Vec::from(body)).header("Content-Type", "text/plain; charset=utf-8")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Writes the response. Connections aren't reused, so the length of the body is always sent,
+    /// except for `304 NOT MODIFIED` where it would have to be the length of the unsent file.
+    pub fn write_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !self.status.starts_with("304") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        out.write_all(head.as_bytes())?;
+        match self.body {
+            Body::Bytes(ref bytes) => out.write_all(bytes)?,
+            Body::File{ ref mut file, start, len } => {
+                file.seek(SeekFrom::Start(start))?;
+                io::copy(&mut file.take(len), out)?;
+            },
+        }
+        out.flush()
+    }
+}
+
+fn read_error(err: io::Error) -> &'static str {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => "408 REQUEST TIMEOUT",
+        _ => "400 BAD REQUEST",
+    }
+}
+
+/// Finds the blank line ending the request head, returning where the head ends and the body starts.
+fn find_head_end(buf: &[u8]) -> Option<(usize, usize)> {
+    (0..buf.len()).find_map(|i| {
+        if buf[i..].starts_with(b"\r\n\r\n") {
+            Some((i, i + 4))
+        } else if buf[i..].starts_with(b"\n\n") {
+            Some((i, i + 2))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ Request, Response };
+
+    fn read(req: &str, max_header: usize, max_body: usize) -> Result<Option<Request>, &'static str> {
+        Request::read(&mut req.as_bytes(), max_header, max_body)
+    }
+
+    #[test]
+    fn test_read() {
+        let req = read("POST /add.ml?x=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 7\r\n\r\na=1&b=2", 1024, 1024).unwrap().unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.target, "/add.ml?x=1");
+        assert_eq!(req.version, "HTTP/1.1");
+        assert_eq!(req.header("Content-Length"), Some("7"));
+        assert_eq!(req.header("host"), Some("localhost"));
+        assert_eq!(req.body, b"a=1&b=2");
+        assert_eq!(read("", 1024, 1024), Ok(None));
+    }
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(read("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", 16, 1024), Err("431 REQUEST HEADER FIELDS TOO LARGE"));
+        assert_eq!(read("POST / HTTP/1.1\r\nContent-Length: 2048\r\n\r\n", 1024, 1024), Err("413 PAYLOAD TOO LARGE"));
+        assert_eq!(read("GET\r\n\r\n", 1024, 1024), Err("400 BAD REQUEST"));
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut out = Vec::new();
+        Response::text("404 NOT FOUND", "Not found\r\n").write_to(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 404 NOT FOUND\r\nContent-Type: text/plain; charset=utf-8\r\n\
+            Content-Length: 11\r\nConnection: close\r\n\r\nNot found\r\n");
+    }
+}
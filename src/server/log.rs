@@ -0,0 +1,187 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{ Duration, SystemTime };
+use super::date::UtcTime;
+use super::http::Request;
+use json::quote as json_str;
+
+/// Verbosity of the diagnostic messages written to stderr.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Info,
+    Debug,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// Common Log Format
+    Common,
+    /// Combined Log Format, which adds the referer and user agent to `Common`
+    Combined,
+    /// One JSON object per line, which also includes the duration and worker
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogTarget {
+    Off,
+    Stdout,
+    Stderr,
+    File(PathBuf),
+}
+
+/// Everything recorded about a single request in the access log.
+pub struct AccessEntry<'a> {
+    pub time: SystemTime,
+    pub client: Option<IpAddr>,
+    /// The request, if it could be read at all
+    pub request: Option<&'a Request>,
+    pub status: &'a str,
+    pub bytes: u64,
+    pub duration: Duration,
+    pub worker: Option<usize>,
+}
+
+impl<'a> AccessEntry<'a> {
+    pub fn format(&self, format: LogFormat) -> String {
+        let client = self.client.map_or(String::from("-"), |c| c.to_string());
+        let code = self.status.split(' ').next().unwrap_or("-");
+        let method = self.request.map_or("-", |r| r.method.as_str());
+        let target = self.request.map_or("-", |r| r.target.as_str());
+        let version = self.request.map_or("-", |r| r.version.as_str());
+        let header = |name| self.request.and_then(|r| r.header(name));
+        match format {
+            LogFormat::Common | LogFormat::Combined => {
+                let mut line = format!("{} - - [{}] \"{} {} {}\" {} {}",
+                    client, UtcTime::from(self.time).clf(), clf_escape(method), clf_escape(target), clf_escape(version), code, self.bytes);
+                if format == LogFormat::Combined {
+                    line.push_str(&format!(" \"{}\" \"{}\"",
+                        clf_escape(header("Referer").unwrap_or("-")), clf_escape(header("User-Agent").unwrap_or("-"))));
+                }
+                line
+            },
+            LogFormat::Json => format!(
+                "{{\"timestamp\":{},\"client\":{},\"method\":{},\"path\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\"worker\":{},\"referer\":{},\"user_agent\":{}}}",
+                json_str(&UtcTime::from(self.time).iso8601()), json_str(&client), json_str(method), json_str(target),
+                code, self.bytes, self.duration.as_secs_f64() * 1000.0,
+                self.worker.map_or(String::from("null"), |w| w.to_string()),
+                header("Referer").map_or(String::from("null"), json_str), header("User-Agent").map_or(String::from("null"), json_str)),
+        }
+    }
+}
+
+/// Escapes a field quoted in the Common Log Format as nginx and Apache do, writing `"`, `\` and
+/// control characters as `\xHH` so that they can't break the line into other fields.
+fn clf_escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '"' | '\\' | '\x00'..='\x1f' | '\x7f' => out.push_str(&format!("\\x{:02X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Writes the access log and diagnostic messages, shared between all the workers.
+pub struct Logger {
+    level: LogLevel,
+    format: LogFormat,
+    access: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Logger {
+    pub fn new(level: LogLevel, format: LogFormat, target: &LogTarget) -> io::Result<Logger> {
+        let access: Option<Box<dyn Write + Send>> = match target {
+            LogTarget::Off => None,
+            LogTarget::Stdout => Some(Box::new(io::stdout())),
+            LogTarget::Stderr => Some(Box::new(io::stderr())),
+            LogTarget::File(path) => Some(Box::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        };
+        Ok(Logger{ level, format, access: access.map(Mutex::new) })
+    }
+
+    pub fn access(&self, entry: &AccessEntry) {
+        if let Some(ref access) = self.access {
+            let line = entry.format(self.format);
+            let mut out = access.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = writeln!(out, "{}", line).and_then(|_| out.flush());
+        }
+    }
+
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        level != LogLevel::Off && level <= self.level
+    }
+
+    pub fn error(&self, message: &str) {
+        self.log(LogLevel::Error, message);
+    }
+
+    pub fn info(&self, message: &str) {
+        self.log(LogLevel::Info, message);
+    }
+
+    pub fn debug(&self, message: &str) {
+        self.log(LogLevel::Debug, message);
+    }
+
+    fn log(&self, level: LogLevel, message: &str) {
+        if self.enabled(level) {
+            eprintln!("{} [{:?}] {}", UtcTime::from(SystemTime::now()).iso8601(), level, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ AccessEntry, LogFormat };
+    use server::http::Request;
+    use std::time::{ Duration, UNIX_EPOCH };
+
+    #[test]
+    fn test_format() {
+        let request = Request {
+            method: String::from("GET"),
+            target: String::from("/add.ml?a=\"1\""),
+            version: String::from("HTTP/1.1"),
+            headers: vec![(String::from("User-Agent"), String::from("curl/8.0 \\\"\n"))],
+            body: Vec::new(),
+        };
+        let entry = AccessEntry {
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            client: Some("127.0.0.1".parse().unwrap()),
+            request: Some(&request),
+            status: "200 OK",
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+            worker: Some(3),
+        };
+        assert_eq!(entry.format(LogFormat::Common),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /add.ml?a=\\x221\\x22 HTTP/1.1\" 200 2326");
+        assert_eq!(entry.format(LogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /add.ml?a=\\x221\\x22 HTTP/1.1\" 200 2326 \"-\" \"curl/8.0 \\x5C\\x22\\x0A\"");
+        assert_eq!(entry.format(LogFormat::Json),
+            "{\"timestamp\":\"2000-10-10T13:55:36.000Z\",\"client\":\"127.0.0.1\",\"method\":\"GET\",\"path\":\"/add.ml?a=\\\"1\\\"\",\
+             \"status\":200,\"bytes\":2326,\"duration_ms\":1.500,\"worker\":3,\"referer\":null,\"user_agent\":\"curl/8.0 \\\\\\\"\\n\"}");
+    }
+
+    #[test]
+    fn test_format_bad_request() {
+        let entry = AccessEntry {
+            time: UNIX_EPOCH,
+            client: None,
+            request: None,
+            status: "400 BAD REQUEST",
+            bytes: 0,
+            duration: Duration::from_millis(2),
+            worker: None,
+        };
+        assert_eq!(entry.format(LogFormat::Common), "- - - [01/Jan/1970:00:00:00 +0000] \"- - -\" 400 0");
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+use eval::ErrorKind;
+use super::template::CacheStats;
+use super::thread_pool::PoolMetrics;
+
+/// Upper bounds of the request duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 11] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// Counts collected while serving, reported in the Prometheus text format by `/_metrics`.
+pub struct Metrics {
+    started: Instant,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    /// Requests by status code
+    requests: BTreeMap<u16, u64>,
+    /// Requests by the first bucket their duration fits in, the last one being for slower requests
+    durations: [u64; DURATION_BUCKETS.len() + 1],
+    duration_sum: Duration,
+    script_errors: BTreeMap<String, u64>,
+    rejected: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics{ started: Instant::now(), counts: Mutex::new(Counts::default()) }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Counts a response by the code its status starts with, such as `404` for `404 NOT FOUND`.
+    pub fn record_request(&self, status: &str, duration: Duration) {
+        let code = status.split(' ').next().and_then(|code| code.parse().ok()).unwrap_or(0);
+        let bucket = DURATION_BUCKETS.iter().position(|le| duration.as_secs_f64() <= *le).unwrap_or(DURATION_BUCKETS.len());
+        let mut counts = self.counts.lock().unwrap();
+        *counts.requests.entry(code).or_insert(0) += 1;
+        counts.durations[bucket] += 1;
+        counts.duration_sum += duration;
+    }
+
+    pub fn record_script_error(&self, kind: &ErrorKind) {
+        // Scripts name the kinds of what they throw, which would make for any number of series
+        let label = match kind {
+            ErrorKind::Thrown(_) => "Thrown",
+            other => other.name(),
+        };
+        *self.counts.lock().unwrap().script_errors.entry(String::from(label)).or_insert(0) += 1;
+    }
+
+    pub fn record_rejected(&self) {
+        self.counts.lock().unwrap().rejected += 1;
+    }
+
+    pub fn render(&self, pool: PoolMetrics, cache: CacheStats) -> String {
+        let counts = self.counts.lock().unwrap();
+        let mut out = String::new();
+        metric(&mut out, "monkeylang_requests_total", "counter", "Requests answered, by response status.");
+        for (code, count) in &counts.requests {
+            let _ = writeln!(out, "monkeylang_requests_total{{status=\"{}\"}} {}", code, count);
+        }
+        metric(&mut out, "monkeylang_request_duration_seconds", "histogram", "Time taken to read, handle and answer requests.");
+        let mut cumulative = 0;
+        for (le, count) in DURATION_BUCKETS.iter().zip(&counts.durations) {
+            cumulative += count;
+            let _ = writeln!(out, "monkeylang_request_duration_seconds_bucket{{le=\"{}\"}} {}", le, cumulative);
+        }
+        let total = counts.durations.iter().sum::<u64>();
+        let _ = writeln!(out, "monkeylang_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", total);
+        let _ = writeln!(out, "monkeylang_request_duration_seconds_sum {}", counts.duration_sum.as_secs_f64());
+        let _ = writeln!(out, "monkeylang_request_duration_seconds_count {}", total);
+        metric(&mut out, "monkeylang_script_errors_total", "counter", "Scripts that failed, by kind of error.");
+        for (kind, count) in &counts.script_errors {
+            let _ = writeln!(out, "monkeylang_script_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+        let values = vec![
+            ("monkeylang_rejected_connections_total", "counter", "Connections turned away because the queue was full.", counts.rejected),
+            ("monkeylang_workers", "gauge", "Worker threads in the pool.", pool.workers as u64),
+            ("monkeylang_workers_busy", "gauge", "Workers handling a connection.", pool.busy as u64),
+            ("monkeylang_queue_length", "gauge", "Connections waiting for a worker.", pool.queued as u64),
+            ("monkeylang_queue_capacity", "gauge", "Connections that may wait for a worker.", pool.capacity as u64),
+            ("monkeylang_worker_panics_total", "counter", "Connections whose handling panicked.", pool.panicked as u64),
+            ("monkeylang_template_cache_hits_total", "counter", "Templates found compiled in the cache.", cache.hits as u64),
+            ("monkeylang_template_cache_misses_total", "counter", "Templates compiled because they weren't cached or changed.", cache.misses as u64),
+            ("monkeylang_templates_cached", "gauge", "Compiled templates in the cache.", cache.templates as u64),
+            ("monkeylang_uptime_seconds", "gauge", "Time since the server started.", self.uptime().as_secs()),
+        ];
+        for (name, kind, help, value) in values {
+            metric(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use eval::ErrorKind;
+    use server::template::CacheStats;
+    use server::thread_pool::PoolMetrics;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_request("200 OK", Duration::from_millis(3));
+        metrics.record_request("200 OK", Duration::from_millis(30));
+        metrics.record_request("404 NOT FOUND", Duration::from_secs(20));
+        metrics.record_script_error(&ErrorKind::LimitExceeded);
+        metrics.record_script_error(&ErrorKind::Thrown(String::from("NotFound")));
+        metrics.record_rejected();
+        let pool = PoolMetrics{ workers: 4, busy: 1, queued: 0, capacity: 8, completed: 3, panicked: 0 };
+        let text = metrics.render(pool, CacheStats{ hits: 5, misses: 2, templates: 2 });
+        for line in &[
+            "# TYPE monkeylang_requests_total counter",
+            "monkeylang_requests_total{status=\"200\"} 2",
+            "monkeylang_requests_total{status=\"404\"} 1",
+            "monkeylang_request_duration_seconds_bucket{le=\"0.001\"} 0",
+            "monkeylang_request_duration_seconds_bucket{le=\"0.005\"} 1",
+            "monkeylang_request_duration_seconds_bucket{le=\"0.05\"} 2",
+            "monkeylang_request_duration_seconds_bucket{le=\"10\"} 2",
+            "monkeylang_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "monkeylang_request_duration_seconds_sum 20.033",
+            "monkeylang_request_duration_seconds_count 3",
+            "monkeylang_script_errors_total{kind=\"LimitExceeded\"} 1",
+            "monkeylang_script_errors_total{kind=\"Thrown\"} 1",
+            "monkeylang_rejected_connections_total 1",
+            "monkeylang_workers_busy 1",
+            "monkeylang_template_cache_hits_total 5",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "{} missing from\n{}", line, text);
+        }
+    }
+}
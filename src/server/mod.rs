@@ -1,81 +1,342 @@
-use std::fs::File;
-use std::env;
+use std::io;
 use std::io::{ Read, Write };
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::net::{ TcpListener, TcpStream, SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use signal_hook::consts::{ SIGINT, SIGTERM };
+use signal_hook::iterator::Signals;
 use std::path::PathBuf;
-use std::collections::VecDeque;
-use lexer::{ Token, Lexer, TokenLexer };
-use parser::Parser;
-use eval::{ State, Eval, Value };
+use eval::{ State, Value, RuntimeError, ErrorKind, TemplateLoader };
+use ast::Program;
+use json;
+use escape;
+use std::rc::Rc;
 use std::collections::HashMap;
+use std::time::{ Duration, Instant, SystemTime };
 
 mod thread_pool;
+mod http;
+mod config;
+mod date;
+mod log;
+mod template;
+mod router;
+mod files;
+mod metrics;
+#[cfg(feature = "tls")]
+mod tls;
 
-pub fn serve(interface: String, port: u16) {
-    let listener = TcpListener::bind(format!("{}:{}", interface, port)).unwrap();
+pub use self::config::{ ServerConfig, Overflow };
+pub use self::thread_pool::PoolMetrics;
+pub use self::log::{ LogLevel, LogFormat, LogTarget };
+pub use self::template::compile as compile_template;
+pub use self::template::tokenize as tokenize_template;
+use self::http::{ Request, Response };
+use self::log::{ Logger, AccessEntry };
+use self::template::{ TemplateCache, TemplateError };
+use self::router::{ Router, RouteMatch };
+use self::metrics::Metrics;
 
-    println!("Serving to {} at port {}", interface, port);
+/// Names bound for pages besides the builtins, see `respond`.
+pub const GLOBALS: &[&str] = &["get", "post", "body", "params"];
 
-    let pool = thread_pool::ThreadPool::new(8);
-    for stream in listener.incoming() {
-        let stream = match stream {
-            Ok(s) => {s},
-            Err(_) => {continue},
-        };
-        println!("New connection from {}", stream.peer_addr().unwrap());
-
-        pool.execute(move|| handle_connection(stream));
-    }
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 40960];
-
-    let read_size = stream.read(&mut buffer).unwrap();
-    let buffer = &buffer[0..read_size];
-    let buf_utf = String::from_utf8_lossy(&buffer[..]);
-    let mut lines = buf_utf.lines();
-    match lines.next() {
-        None => {},
-        Some(req) => {
-            println!("Request: {}", req);
-            let mut parts = req.split(' ');
-            let contents = match parts.next() {
-                Some(verb) if verb == "GET" || verb == "POST" => {
-                    parts.next().and_then(|part| {
-                        let (path_str, get_args) = parse_get_args(part);
-                        let post_args = if verb == "POST" {
-                            while let Some(l) = lines.next() {
-                                if l.trim().len() == 0 {
-                                    break;
-                                }
-                            }
-                            lines.next().map(parse_form_args).unwrap_or(Vec::new())
-                        } else {
-                            Vec::new()
-                        };
-                        let cwd = env::current_dir().ok()?;
-                        let public_path = cwd.join("public");
-                        let file_path = public_path.join(path_str);
-                        let path = file_path.canonicalize().ok()?;
-                        if path.starts_with(public_path) {
-                            parse_file(path, get_args, post_args)
-                        } else {
-                            None
-                        }
-                    })
-                },
-                _ => None,
+/// State shared by all the workers of a server.
+struct Context {
+    config: ServerConfig,
+    logger: Logger,
+    templates: TemplateCache,
+    router: Router,
+    pool: thread_pool::Monitor,
+    metrics: Metrics,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+/// Handle to a server started with `start`, used to stop it.
+pub struct Server {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    acceptor: thread::JoinHandle<thread_pool::ThreadPool>,
+    ctx: Arc<Context>,
+}
+
+impl Server {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn pool_metrics(&self) -> PoolMetrics {
+        self.ctx.pool.metrics()
+    }
+
+    /// Stops accepting connections and waits up to `timeout` for in-flight requests to complete.
+    /// Returns false if some requests were still running when the timeout expired.
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        self.running.store(false, Ordering::SeqCst);
+        // The acceptor is blocked waiting for a connection, so give it one to notice the flag
+        let mut wake_addr = self.addr;
+        if wake_addr.ip().is_unspecified() {
+            wake_addr.set_ip(if wake_addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+        }
+        let _ = TcpStream::connect(wake_addr);
+        match self.acceptor.join() {
+            Ok(pool) => pool.shutdown(timeout),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Starts serving in the background and returns a handle to the running server.
+pub fn start(mut config: ServerConfig) -> io::Result<Server> {
+    config.root = config.root.canonicalize()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", config.root.display(), e)))?;
+    let logger = Logger::new(config.log_level, config.log_format, &config.access_log)?;
+    let router = match config.routes {
+        Some(ref path) => Router::load_file(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        None => Router::default(),
+    };
+    if config.tls_cert.is_some() != config.tls_key.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "tls_cert and tls_key must be set together"));
+    }
+    #[cfg(feature = "tls")]
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+        _ => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    {
+        if config.tls_cert.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "TLS support is not built in, rebuild with --features tls"));
+        }
+    }
+    let listener = TcpListener::bind(format!("{}:{}", config.interface, config.port))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}:{}: {}", config.interface, config.port, e)))?;
+    let addr = listener.local_addr()?;
+    let running = Arc::new(AtomicBool::new(true));
+
+    let scheme = if config.tls_cert.is_some() { "HTTPS" } else { "HTTP" };
+    logger.info(&format!("Serving {} to {} at port {} over {} with {} workers",
+        config.root.display(), config.interface, addr.port(), scheme, config.threads));
+
+    let mut pool = thread_pool::ThreadPool::new(config.threads, config.queue_size);
+    let ctx = Arc::new(Context {
+        config, logger, router,
+        templates: TemplateCache::new(),
+        pool: pool.monitor(),
+        metrics: Metrics::new(),
+        #[cfg(feature = "tls")]
+        tls,
+    });
+    let acceptor_running = Arc::clone(&running);
+    let acceptor_ctx = Arc::clone(&ctx);
+    let acceptor = thread::spawn(move || {
+        let ctx = acceptor_ctx;
+        for stream in listener.incoming() {
+            if !acceptor_running.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(s) => {s},
+                Err(_) => {continue},
             };
-            let response = format!("HTTP/1.1 {}\r\n\r\n{}", if contents.is_some() {
-                "200 OK"
-            } else {
-                "404 NOT FOUND"
-            }, contents.unwrap_or(String::from("Not found\r\n")));
-            stream.write(response.as_bytes()).unwrap();
-            stream.flush().unwrap();
+
+            if ctx.config.overflow == Overflow::Reject && pool.is_full() {
+                reject(stream, &ctx);
+                continue;
+            }
+            let ctx = Arc::clone(&ctx);
+            pool.execute(move|| handle_connection(stream, ctx));
+        }
+        pool
+    });
+    Ok(Server{ addr, running, acceptor, ctx })
+}
+
+/// Serves until the process receives SIGINT or SIGTERM, then shuts down gracefully.
+pub fn serve(config: ServerConfig) -> io::Result<()> {
+    let shutdown_timeout = config.shutdown_timeout;
+    let server = start(config)?;
+    let ctx = Arc::clone(&server.ctx);
+    let logger = &ctx.logger;
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            server.shutdown(shutdown_timeout);
+            return Err(err);
+        },
+    };
+    if let Some(signal) = signals.forever().next() {
+        logger.info(&format!("Received signal {}, shutting down", signal));
+    }
+    if !server.shutdown(shutdown_timeout) {
+        logger.error("Timed out waiting for requests to complete");
+    }
+    Ok(())
+}
+
+/// Turns a connection away without reading the request, as no worker is free to handle it.
+fn reject(mut stream: TcpStream, ctx: &Context) {
+    ctx.metrics.record_rejected();
+    let metrics = ctx.pool.metrics();
+    ctx.logger.error(&format!("Rejected connection from {}: {} workers busy and {} connections queued",
+        stream.peer_addr().map_or(String::from("-"), |addr| addr.ip().to_string()), metrics.busy, metrics.queued));
+    // The acceptor writes this itself, so don't let a slow client hold it up
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+    let mut response = Response::text("503 SERVICE UNAVAILABLE", "Service unavailable\r\n").header("Retry-After", "1");
+    let _ = response.write_to(&mut stream);
+}
+
+fn handle_connection(mut stream: TcpStream, ctx: Arc<Context>) {
+    let (config, logger) = (&ctx.config, &ctx.logger);
+    let time = SystemTime::now();
+    let started = Instant::now();
+    let client = stream.peer_addr().ok().map(|addr| addr.ip());
+    if logger.enabled(LogLevel::Debug) {
+        logger.debug(&format!("Worker {} handling connection from {}",
+            thread_pool::worker_id().map_or(String::from("-"), |id| id.to_string()),
+            client.map_or(String::from("-"), |ip| ip.to_string())));
+    }
+    let _ = stream.set_read_timeout(config.read_timeout);
+    let _ = stream.set_write_timeout(config.write_timeout);
+    #[cfg(feature = "tls")]
+    {
+        if let Some(ref tls_config) = ctx.tls {
+            match tls::accept(tls_config, stream) {
+                Ok(mut stream) => {
+                    exchange(&mut stream, client, time, started, &ctx);
+                    stream.conn.send_close_notify();
+                    let _ = stream.flush();
+                },
+                Err(err) => logger.debug(&format!("TLS handshake with {} failed: {}",
+                    client.map_or(String::from("-"), |ip| ip.to_string()), err)),
+            }
+            return;
+        }
+    }
+    exchange(&mut stream, client, time, started, &ctx);
+}
+
+/// Reads a request from a connection, answers it and logs the exchange.
+fn exchange<S: Read + Write>(stream: &mut S, client: Option<IpAddr>, time: SystemTime, started: Instant, ctx: &Arc<Context>) {
+    let (config, logger) = (&ctx.config, &ctx.logger);
+    let (request, mut response) = match Request::read(stream, config.max_header_size, config.max_body_size) {
+        Ok(Some(request)) => {
+            let response = respond(ctx, &request);
+            (Some(request), response)
+        },
+        Ok(None) => return,
+        Err(status) => (None, Response::new(status, Vec::new())),
+    };
+    if let Err(err) = response.write_to(stream) {
+        logger.debug(&format!("Could not write response: {}", err));
+    }
+    let duration = started.elapsed();
+    ctx.metrics.record_request(response.status, duration);
+    logger.access(&AccessEntry {
+        time, client,
+        status: response.status,
+        request: request.as_ref(),
+        bytes: response.body.len(),
+        duration,
+        worker: thread_pool::worker_id(),
+    });
+}
+
+fn respond(ctx: &Arc<Context>, request: &Request) -> Response {
+    let (path, get_args) = parse_get_args(&request.target);
+    // Links to files with spaces and such in their names are percent-encoded, as in listings.
+    // Decoded paths are still checked to be under the root once resolved.
+    let path = escape::decode_url(path);
+    let path_str = path.as_str();
+    if ctx.config.status_endpoints && request.method == "GET" {
+        match path_str {
+            "_health" => return Response::text("200 OK", "OK\r\n"),
+            "_metrics" => {
+                let text = ctx.metrics.render(ctx.pool.metrics(), ctx.templates.stats());
+                return Response::new("200 OK", text.into_bytes()).header("Content-Type", "text/plain; version=0.0.4; charset=utf-8");
+            },
+            _ => {},
+        }
+    }
+    let body = String::from_utf8_lossy(&request.body);
+    let json_body = request.header("Content-Type").is_some_and(is_json);
+    let post_args = if request.method == "POST" && !json_body && !body.trim().is_empty() {
+        parse_form_args(&body)
+    } else {
+        Vec::new()
+    };
+    // JSON bodies are decoded for the script, any other body is passed on as a string
+    let body = if json_body && !body.trim().is_empty() {
+        match json::decode(&body) {
+            Ok(body) => body,
+            Err(err) => return Response::text("400 BAD REQUEST", &format!("{}\r\n", err)),
+        }
+    } else {
+        Value::Str(body.to_string())
+    };
+    let mut globals = vec![("get", args_hash(get_args)), ("post", args_hash(post_args)), ("body", body)];
+    let contents = match ctx.router.route(&request.method, path_str) {
+        RouteMatch::Found(handler, params) => {
+            globals.push(("params", Value::Hash(params.into_iter().map(|(k, v)| (k, parse_value(&v))).collect())));
+            match resolve_path(&ctx.config, &handler.script) {
+                Some(path) => parse_file(ctx, request, path, globals, handler.function.as_deref()),
+                None => {
+                    ctx.logger.error(&format!("Script {} handling {} not found", handler.script, request.target));
+                    None
+                },
+            }
+        },
+        RouteMatch::MethodNotAllowed => return Response::text("405 METHOD NOT ALLOWED", "Method not allowed\r\n"),
+        RouteMatch::NotFound => match request.method.as_str() {
+            "GET" | "POST" => {
+                globals.push(("params", Value::Hash(HashMap::new())));
+                resolve_path(&ctx.config, path_str).and_then(|path| parse_file(ctx, request, path, globals, None))
+            },
+            _ => None,
+        },
+    };
+    match contents {
+        Some(Ok(response)) => response,
+        Some(Err(err)) => {
+            ctx.logger.error(&format!("Script error in {}: {}", request.target, err));
+            ctx.metrics.record_script_error(&err.kind);
+            match err.kind {
+                ErrorKind::LimitExceeded => Response::text("503 SERVICE UNAVAILABLE", "Service unavailable\r\n"),
+                _ => Response::text("500 INTERNAL SERVER ERROR", "Internal server error\r\n"),
+            }
         },
+        None => Response::text("404 NOT FOUND", "Not found\r\n"),
+    }
+}
+
+/// Loads the templates included by pages, from under the document root.
+struct RootLoader(Arc<Context>);
+
+impl TemplateLoader for RootLoader {
+    fn load(&self, path: &str) -> Result<Arc<Program>, RuntimeError> {
+        let not_found = || RuntimeError::new(ErrorKind::Template, &format!("Template {} not found", path));
+        let file = resolve_path(&self.0.config, path.trim_start_matches('/')).ok_or_else(not_found)?;
+        match self.0.templates.get(&file) {
+            Ok(program) => Ok(program),
+            Err(TemplateError::Syntax(err)) => Err(RuntimeError::new(ErrorKind::Syntax, &format!("{}: {}", path, err))),
+            Err(TemplateError::Io(_)) => Err(not_found()),
+        }
+    }
+}
+
+/// Maps a request path onto a file under the document root, trying the index files for directories.
+/// Directories without an index file are returned themselves if they may be listed.
+fn resolve_path(config: &ServerConfig, path: &str) -> Option<PathBuf> {
+    let path = config.root.join(path).canonicalize().ok()?;
+    if !path.starts_with(&config.root) {
+        return None;
+    }
+    if path.is_dir() {
+        let index = config.index_files.iter().map(|index| path.join(index)).find(|index| index.is_file());
+        index.or(if config.list_directories { Some(path) } else { None })
+    } else {
+        Some(path)
     }
 }
 
@@ -83,7 +344,7 @@ fn parse_get_args(req: &str) -> (&str, Vec<(&str, &str)>) {
     let mut split = req.split('?');
     let path = split.next().unwrap();
     let args = split.next().map(parse_form_args);
-    (if path == "/" { "index.ml" } else { &path[1..] }, args.unwrap_or(Vec::new()))
+    (path.trim_start_matches('/'), args.unwrap_or(Vec::new()))
 }
 
 fn parse_form_args(args: &str) -> Vec<(&str, &str)> {
@@ -95,58 +356,71 @@ fn parse_form_args(args: &str) -> Vec<(&str, &str)> {
     }).collect::<Vec<(&str, &str)>>()
 }
 
-struct ScriptLexer(VecDeque<Token>);
+/// Whether a `Content-Type` is JSON, such as `application/json; charset=utf-8`.
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    media_type == "application/json" || media_type.ends_with("+json")
+}
 
-impl TokenLexer for ScriptLexer {
-    fn next_token(&mut self) -> Token {
-        self.0.pop_front().unwrap_or(Token::Eof)
-    }
+fn args_hash(args: Vec<(&str, &str)>) -> Value {
+    Value::Hash(args.into_iter().map(|(k, v)| (String::from(k), parse_value(v))).collect())
 }
 
-fn parse_file(path: PathBuf, get_args: Vec<(&str, &str)>, post_args: Vec<(&str, &str)>) -> Option<String> {
-    let mut contents = String::new();
-    File::open(path.clone()).ok()?.read_to_string(&mut contents).ok()?;
+/// Runs a script with `globals` bound, followed by a call to `function` with the `params` global if given.
+/// A function returning a hash or an array responds with it as JSON. Other files are served as they are,
+/// and directories are listed.
+fn parse_file(ctx: &Arc<Context>, request: &Request, path: PathBuf, globals: Vec<(&str, Value)>, function: Option<&str>) -> Option<Result<Response, RuntimeError>> {
+    if path.is_dir() {
+        return files::list_directory(&path, &escape::decode_url(parse_get_args(&request.target).0)).ok().map(Ok);
+    }
     match path.extension() {
         Some(ext) if ext == "ml" => {
-            let mut line_buf = VecDeque::new();
-            let mut is_ml = false;
-            contents.lines().for_each(|line| {
-                if is_ml {
-                    if line.trim() == "%>" {
-                        is_ml = false;
-                    } else {
-                        for tok in Lexer::lex_str(line) {
-                            line_buf.push_back(tok);
-                        }
-                    }
-                } else if line.trim() == "<%" {
-                    is_ml = true;
-                } else {
-                    line_buf.push_back(Token::Ident(String::from("println")));
-                    line_buf.push_back(Token::Lparen);
-                    line_buf.push_back(Token::String(String::from(line)));
-                    line_buf.push_back(Token::Rparen);
-                }
-            });
-            let program = Parser::new(&mut ScriptLexer(line_buf)).parse_program();
+            let program = match ctx.templates.get(&path) {
+                Ok(program) => program,
+                Err(TemplateError::Syntax(err)) => return Some(Err(RuntimeError::new(ErrorKind::Syntax, &err.to_string()))),
+                Err(TemplateError::Io(err)) => {
+                    ctx.logger.debug(&format!("Could not read {}: {}", path.display(), err));
+                    return None;
+                },
+            };
             let mut state = State::new();
-            let mut get_map = HashMap::new();
-            let mut post_map = HashMap::new();
-            for (k, v) in get_args {
-                get_map.insert(String::from(k), parse_value(v));
-            }
-            for (k, v) in post_args {
-                post_map.insert(String::from(k), parse_value(v));
+            for (name, value) in globals {
+                state.set(&String::from(name), value);
             }
-            state.set(&String::from("get"), Value::Hash(get_map));
-            state.set(&String::from("post"), Value::Hash(post_map));
+            state.set_limits(ctx.config.limits);
+            state.escape_output();
+            state.set_loader(Rc::new(RootLoader(Arc::clone(ctx))));
 
             let mut output: Vec<u8> = Vec::new();
-            program.eval(&mut state, &mut output).map(|_| {
-                String::from_utf8_lossy(&output).to_string()
+            // A script ending with a `let` evaluates to nothing, which is still a page
+            let mut rv = state.render(&program, &mut output).unwrap_or(Value::Null);
+            if let (Some(name), false) = (function, matches!(rv, Value::Error(_))) {
+                let params = state.get(&String::from("params")).cloned().unwrap_or(Value::Null);
+                rv = match state.call(name, vec![params], &mut output) {
+                    Some(Value::Null) => Value::Null,
+                    Some(Value::Error(err)) => Value::Error(err),
+                    Some(val @ Value::Hash(_)) | Some(val @ Value::Array(_)) => return Some(match json::encode(&val) {
+                        Ok(body) => Ok(Response::new("200 OK", body.into_bytes()).header("Content-Type", "application/json")),
+                        Err(err) => Err(RuntimeError::new(ErrorKind::Json, &err)),
+                    }),
+                    // Whatever the handler returns is written after what it printed
+                    Some(val) => state.call("print", vec![val], &mut output).unwrap_or(Value::Null),
+                    None => Value::Error(RuntimeError::new(ErrorKind::Template,
+                        &format!("Handler function {} is not defined in {}", name, path.display()))),
+                };
+            }
+            Some(match rv {
+                Value::Error(err) => Err(err),
+                _ => Ok(Response::new("200 OK", output).header("Content-Type", "text/html; charset=utf-8")),
             })
         },
-        _ => Some(contents),
+        _ => match files::serve(&path, request) {
+            Ok(response) => Some(Ok(response)),
+            Err(err) => {
+                ctx.logger.debug(&format!("Could not read {}: {}", path.display(), err));
+                None
+            },
+        },
     }
 }
 
@@ -161,3 +435,248 @@ fn parse_value(val: &str) -> Value {
         Value::Str(String::from(val))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{ start, ServerConfig, LogTarget, LogLevel, Overflow };
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::io::{ Read, Write };
+    use std::net::{ SocketAddr, TcpStream };
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    fn request(addr: SocketAddr, req: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_start_shutdown() {
+        let config = ServerConfig {
+            interface: String::from("127.0.0.1"),
+            port: 0,
+            access_log: LogTarget::Off,
+            ..ServerConfig::default()
+        };
+        let server = start(config).unwrap();
+        let response = request(server.addr(), "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Welcome!"));
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_tls_config() {
+        let config = ServerConfig {
+            interface: String::from("127.0.0.1"),
+            port: 0,
+            tls_cert: Some(PathBuf::from("src/server/testdata/localhost.pem")),
+            access_log: LogTarget::Off,
+            log_level: LogLevel::Off,
+            ..ServerConfig::default()
+        };
+        assert_eq!(start(config.clone()).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        let config = ServerConfig{ tls_key: Some(PathBuf::from("src/server/testdata/localhost.key")), ..config };
+        let started = start(config);
+        if cfg!(feature = "tls") {
+            assert!(started.unwrap().shutdown(Duration::from_secs(5)));
+        } else {
+            assert!(started.err().unwrap().to_string().contains("--features tls"));
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_https() {
+        use rustls::{ ClientConfig, ClientConnection, RootCertStore, StreamOwned };
+        use rustls::crypto::ring;
+        use rustls::pki_types::ServerName;
+        use rustls_pemfile;
+        use std::convert::TryFrom;
+        use std::sync::Arc;
+
+        let config = ServerConfig {
+            interface: String::from("127.0.0.1"),
+            port: 0,
+            tls_cert: Some(PathBuf::from("src/server/testdata/localhost.pem")),
+            tls_key: Some(PathBuf::from("src/server/testdata/localhost.key")),
+            access_log: LogTarget::Off,
+            log_level: LogLevel::Off,
+            ..ServerConfig::default()
+        };
+        let server = start(config).unwrap();
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &include_bytes!("testdata/localhost.pem")[..]) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = ClientConnection::new(Arc::new(client_config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(server.addr()).unwrap());
+        stream.write_all(b"GET /_health HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("\r\n\r\nOK\r\n"), "{}", response);
+
+        // Plain HTTP gets nowhere, and doesn't take the server down either
+        let mut plain = TcpStream::connect(server.addr()).unwrap();
+        plain.write_all(b"GET /_health HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        let _ = plain.read_to_end(&mut response);
+        assert!(!String::from_utf8_lossy(&response).contains("200 OK"));
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_status_endpoints() {
+        let config = ServerConfig {
+            interface: String::from("127.0.0.1"),
+            port: 0,
+            access_log: LogTarget::Off,
+            log_level: LogLevel::Off,
+            ..ServerConfig::default()
+        };
+        let server = start(config).unwrap();
+        assert!(request(server.addr(), "GET /_health HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nOK\r\n"));
+        request(server.addr(), "GET /missing.ml HTTP/1.1\r\n\r\n");
+        request(server.addr(), "GET / HTTP/1.1\r\n\r\n");
+        let response = request(server.addr(), "GET /_metrics HTTP/1.1\r\n\r\n");
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"), "{}", response);
+        // Requests are counted once answered, so the health check is in already but not this one
+        assert!(response.contains("\nmonkeylang_requests_total{status=\"200\"} 2\n"), "{}", response);
+        assert!(response.contains("\nmonkeylang_requests_total{status=\"404\"} 1\n"), "{}", response);
+        assert!(response.contains("\nmonkeylang_template_cache_misses_total 2\n"), "{}", response);
+        assert!(response.contains("\nmonkeylang_workers 8\n"), "{}", response);
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_overflow_reject() {
+        let config = ServerConfig {
+            interface: String::from("127.0.0.1"),
+            port: 0,
+            threads: 1,
+            queue_size: 1,
+            overflow: Overflow::Reject,
+            access_log: LogTarget::Off,
+            log_level: LogLevel::Off,
+            ..ServerConfig::default()
+        };
+        let server = start(config).unwrap();
+        // The first connection keeps the only worker waiting for a request, the second one fills the queue
+        let busy = TcpStream::connect(server.addr()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let queued = TcpStream::connect(server.addr()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let metrics = server.pool_metrics();
+        assert_eq!((metrics.busy, metrics.queued), (1, 1));
+        let mut rejected = TcpStream::connect(server.addr()).unwrap();
+        let mut response = String::new();
+        rejected.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 SERVICE UNAVAILABLE"), "{}", response);
+        drop((busy, queued));
+        while server.pool_metrics().queued > 0 || server.pool_metrics().busy > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(request(server.addr(), "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK"));
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = env::temp_dir().join(format!("monkeylang-includes-{}", std::process::id()));
+        fs::create_dir_all(dir.join("root/partials")).unwrap();
+        fs::write(dir.join("secret.ml"), "secret").unwrap();
+        fs::write(dir.join("root/layout.ml"), "<title><% block(\"title\") %></title><% block(\"content\") %>").unwrap();
+        fs::write(dir.join("root/partials/item.ml"), "<li><%= item %></li>").unwrap();
+        fs::write(dir.join("root/page.ml"), "<% layout(\"layout.ml\"); block(\"title\", \"Items\") %>\
+            <% map([1, 2], fn(i) { include(\"/partials/item.ml\", {\"item\": i}) }) %>").unwrap();
+        fs::write(dir.join("root/escape.ml"), "<% include(\"../secret.ml\") %>").unwrap();
+        let config = ServerConfig {
+            interface: String::from("127.0.0.1"),
+            port: 0,
+            root: dir.join("root"),
+            access_log: LogTarget::Off,
+            log_level: LogLevel::Off,
+            ..ServerConfig::default()
+        };
+        let server = start(config).unwrap();
+        let response = request(server.addr(), "GET /page.ml HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\n<title>Items</title><li>1</li><li>2</li>"), "{}", response);
+        let response = request(server.addr(), "GET /escape.ml HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 INTERNAL SERVER ERROR"));
+        assert!(!response.contains("secret"));
+        assert!(server.shutdown(Duration::from_secs(5)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encoded_paths() {
+        let dir = env::temp_dir().join(format!("monkeylang-encoded-{}", std::process::id()));
+        fs::create_dir_all(dir.join("root/sub dir")).unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        fs::write(dir.join("root/sub dir/a file.txt"), "found").unwrap();
+        let config = ServerConfig {
+            interface: String::from("127.0.0.1"),
+            port: 0,
+            root: dir.join("root"),
+            list_directories: true,
+            access_log: LogTarget::Off,
+            log_level: LogLevel::Off,
+            ..ServerConfig::default()
+        };
+        let server = start(config).unwrap();
+        let listing = request(server.addr(), "GET / HTTP/1.1\r\n\r\n");
+        assert!(listing.contains("<a href=\"/sub%20dir/\">"), "{}", listing);
+        let listing = request(server.addr(), "GET /sub%20dir/ HTTP/1.1\r\n\r\n");
+        let href = listing.split("<a href=\"").nth(2).and_then(|rest| rest.split('"').next()).unwrap();
+        assert_eq!(href, "/sub%20dir/a%20file.txt");
+        assert!(request(server.addr(), &format!("GET {} HTTP/1.1\r\n\r\n", href)).ends_with("\r\n\r\nfound"));
+        // Decoded dots still can't lead out of the root
+        assert!(request(server.addr(), "GET /%2e%2e/secret.txt HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(server.shutdown(Duration::from_secs(5)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_routes() {
+        let dir = env::temp_dir().join(format!("monkeylang-routes-{}", std::process::id()));
+        fs::create_dir_all(dir.join("root")).unwrap();
+        fs::write(dir.join("routes"), "GET /users/:id users.ml#show\nPOST /users/:id users.ml#update\n* /about about.ml\n").unwrap();
+        fs::write(dir.join("root/users.ml"), "<% let show = fn(params) { \"User \" + (params[\"id\"] + 1) } %>\
+            <% let update = fn(params) { return {\"id\": params[\"id\"], \"name\": body[\"name\"]} } %>").unwrap();
+        fs::write(dir.join("root/about.ml"), "About <%= get[\"lang\"] %>").unwrap();
+        fs::write(dir.join("root/static.ml"), "Static").unwrap();
+        let config = ServerConfig {
+            interface: String::from("127.0.0.1"),
+            port: 0,
+            root: dir.join("root"),
+            routes: Some(dir.join("routes")),
+            access_log: LogTarget::Off,
+            log_level: LogLevel::Off,
+            ..ServerConfig::default()
+        };
+        let server = start(config).unwrap();
+        assert!(request(server.addr(), "GET /users/41 HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nUser 42"));
+        let response = request(server.addr(), "POST /users/7 HTTP/1.1\r\nContent-Type: application/json\r\n\
+            Content-Length: 21\r\n\r\n{\"name\": \"Ann \\\"A\\\"\"}");
+        assert!(response.contains("Content-Type: application/json\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n{\"id\":7,\"name\":\"Ann \\\"A\\\"\"}"), "{}", response);
+        let response = request(server.addr(), "POST /users/7 HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 1\r\n\r\n{");
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+        assert!(request(server.addr(), "PUT /about?lang=en HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nAbout en"));
+        assert!(request(server.addr(), "DELETE /users/41 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 METHOD NOT ALLOWED"));
+        assert!(request(server.addr(), "GET /static.ml HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nStatic"));
+        assert!(request(server.addr(), "GET /users HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 NOT FOUND"));
+        assert!(server.shutdown(Duration::from_secs(5)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::Path;
+
+/// Script run for a route, optionally followed by a call to one of the functions it defines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Handler {
+    /// Path of the script, relative to the document root
+    pub script: String,
+    pub function: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `:name`, matching any single segment
+    Param(String),
+    /// `*name`, matching the rest of the path
+    Rest(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Route {
+    /// `None` matches any method
+    method: Option<String>,
+    pattern: Vec<Segment>,
+    handler: Handler,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteMatch<'a> {
+    /// The handler of the first matching route, with the parameters captured from the path
+    Found(&'a Handler, Vec<(String, String)>),
+    /// Some route matches the path, but not with this method
+    MethodNotAllowed,
+    NotFound,
+}
+
+/// Maps request methods and paths onto handler scripts, as listed in a routes file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn load_file(path: &Path) -> Result<Router, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read routes file {}: {}", path.display(), e))?;
+        Router::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parses routes written one per line as `METHOD /path handler`, tried in order. `*` matches any method,
+    /// `:name` in the path captures a segment and `*name` the rest of the path. The handler is a script
+    /// under the document root, optionally followed by `#function` to call one of the functions it defines.
+    /// `#` at the start of a line starts a comment.
+    pub fn parse(contents: &str) -> Result<Router, String> {
+        let mut routes = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts = line.split_whitespace().collect::<Vec<&str>>();
+            if parts.len() != 3 {
+                return Err(format!("line {}: expected METHOD /path handler", i + 1));
+            }
+            let method = match parts[0] {
+                "*" => None,
+                method => Some(method.to_uppercase()),
+            };
+            if !parts[1].starts_with('/') {
+                return Err(format!("line {}: path {} should start with /", i + 1, parts[1]));
+            }
+            let pattern = split_path(parts[1]).map(|seg| {
+                if let Some(name) = seg.strip_prefix(':') {
+                    Segment::Param(String::from(name))
+                } else if let Some(name) = seg.strip_prefix('*') {
+                    Segment::Rest(String::from(name))
+                } else {
+                    Segment::Literal(String::from(seg))
+                }
+            }).collect::<Vec<Segment>>();
+            if pattern.iter().rev().skip(1).any(|seg| matches!(seg, Segment::Rest(_))) {
+                return Err(format!("line {}: a * parameter must be the last part of the path {}", i + 1, parts[1]));
+            }
+            let mut handler = parts[2].splitn(2, '#');
+            let script = String::from(handler.next().unwrap());
+            let function = handler.next().map(String::from);
+            routes.push(Route{ method, pattern, handler: Handler{ script, function } });
+        }
+        Ok(Router{ routes })
+    }
+
+    pub fn route(&self, method: &str, path: &str) -> RouteMatch<'_> {
+        let segments = split_path(path).collect::<Vec<&str>>();
+        let mut path_matched = false;
+        for route in &self.routes {
+            if let Some(params) = route.matches(&segments) {
+                if route.method.as_ref().is_none_or(|m| m == method) {
+                    return RouteMatch::Found(&route.handler, params);
+                }
+                path_matched = true;
+            }
+        }
+        if path_matched {
+            RouteMatch::MethodNotAllowed
+        } else {
+            RouteMatch::NotFound
+        }
+    }
+}
+
+impl Route {
+    fn matches(&self, segments: &[&str]) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        for (i, part) in self.pattern.iter().enumerate() {
+            match part {
+                Segment::Rest(name) => {
+                    params.push((name.clone(), segments[i.min(segments.len())..].join("/")));
+                    return Some(params);
+                },
+                Segment::Param(name) => params.push((name.clone(), String::from(*segments.get(i)?))),
+                Segment::Literal(lit) => if segments.get(i) != Some(&lit.as_str()) {
+                    return None;
+                },
+            }
+        }
+        if segments.len() == self.pattern.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|seg| !seg.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ Router, RouteMatch, Handler };
+
+    fn handler(script: &str, function: Option<&str>) -> Handler {
+        Handler{ script: String::from(script), function: function.map(String::from) }
+    }
+
+    fn params(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect()
+    }
+
+    #[test]
+    fn test_route() {
+        let router = Router::parse("
+            # Users
+            GET  /users           users.ml#list
+            GET  /users/:id       users.ml#show
+            post /users/:id/posts posts.ml
+            *    /files/*path     files.ml
+        ").unwrap();
+        let users = handler("users.ml", Some("show"));
+        assert_eq!(router.route("GET", "/users/42/"), RouteMatch::Found(&users, params(&[("id", "42")])));
+        assert_eq!(router.route("GET", "users"), RouteMatch::Found(&handler("users.ml", Some("list")), Vec::new()));
+        let posts = handler("posts.ml", None);
+        assert_eq!(router.route("POST", "/users/7/posts"), RouteMatch::Found(&posts, params(&[("id", "7")])));
+        let files = handler("files.ml", None);
+        assert_eq!(router.route("PUT", "/files/a/b.txt"), RouteMatch::Found(&files, params(&[("path", "a/b.txt")])));
+        assert_eq!(router.route("DELETE", "/users/42"), RouteMatch::MethodNotAllowed);
+        assert_eq!(router.route("GET", "/users/42/comments"), RouteMatch::NotFound);
+        assert_eq!(router.route("GET", "/"), RouteMatch::NotFound);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Router::parse("GET /users").is_err());
+        assert!(Router::parse("GET users users.ml").is_err());
+        assert!(Router::parse("GET /*path/edit files.ml").is_err());
+    }
+}
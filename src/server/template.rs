@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::SystemTime;
+use ast::Program;
+use lexer::{ Token, Lexer, TokenLexer, Position };
+use parser::{ Parser, ParseError };
+
+struct ScriptLexer(VecDeque<(Token, Position)>);
+
+impl TokenLexer for ScriptLexer {
+    fn next_token(&mut self) -> Token {
+        self.next_spanned().0
+    }
+
+    fn next_spanned(&mut self) -> (Token, Position) {
+        self.0.pop_front().unwrap_or((Token::Eof, Position::default()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    /// Literal text, printed as is
+    Text(String),
+    /// `<% code %>`, evaluated
+    Code(String),
+    /// `<%= expr %>`, evaluated and printed
+    Output(String),
+    /// `<%# comment %>`, ignored
+    Comment,
+}
+
+/// Returns the position right after `text`, if it started at `pos`.
+fn advance(mut pos: Position, text: &str) -> Position {
+    for c in text.chars() {
+        if c == '\n' {
+            pos.line += 1;
+            pos.col = 1;
+        } else {
+            pos.col += 1;
+        }
+    }
+    pos
+}
+
+/// Finds the `%>` closing a tag, skipping over any in string literals.
+fn find_tag_end(code: &str, skip_strings: bool) -> Option<usize> {
+    let mut in_str = false;
+    for (i, c) in code.char_indices() {
+        if c == '"' && skip_strings {
+            in_str = !in_str;
+        } else if !in_str && code[i..].starts_with("%>") {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Splits a page into literal text and tags, along with where each of them starts.
+/// `<%-` drops the spaces and tabs before a tag on its line and `-%>` the ones after it, up to and
+/// including the line break; other than that, text is kept exactly as written.
+fn scan(contents: &str) -> Result<Vec<(Segment, Position)>, ParseError> {
+    let mut segments = Vec::new();
+    let mut rest = contents;
+    let mut pos = Position{ line: 1, col: 1 };
+    while let Some(start) = rest.find("<%") {
+        let tag_pos = advance(pos, &rest[..start]);
+        let trim_left = rest[start + 2..].starts_with('-');
+        let text = if trim_left { rest[..start].trim_end_matches([' ', '\t']) } else { &rest[..start] };
+        if !text.is_empty() {
+            segments.push((Segment::Text(String::from(text)), pos));
+        }
+        let marker = if trim_left { 3 } else { 2 };
+        let (kind, marker_len) = match rest[start + marker..].chars().next() {
+            Some('=') => (Segment::Output(String::new()), marker + 1),
+            Some('#') => (Segment::Comment, marker + 1),
+            _ => (Segment::Code(String::new()), marker),
+        };
+        let code = &rest[start + marker_len..];
+        let code_pos = advance(tag_pos, &rest[start..start + marker_len]);
+        let end = find_tag_end(code, kind != Segment::Comment)
+            .ok_or(ParseError{ message: String::from("Unterminated <% tag"), pos: tag_pos })?;
+        let trim_right = code[..end].ends_with('-');
+        let body = if trim_right { &code[..end - 1] } else { &code[..end] };
+        segments.push((match kind {
+            Segment::Output(_) => Segment::Output(String::from(body)),
+            Segment::Code(_) => Segment::Code(String::from(body)),
+            other => other,
+        }, code_pos));
+        let mut tag_end = end + 2;
+        if trim_right {
+            let after = &code[tag_end..];
+            let blanks = after.len() - after.trim_start_matches([' ', '\t']).len();
+            tag_end += blanks;
+            if code[tag_end..].starts_with("\r\n") {
+                tag_end += 2;
+            } else if code[tag_end..].starts_with('\n') {
+                tag_end += 1;
+            }
+        }
+        pos = advance(code_pos, &code[..tag_end]);
+        rest = &code[tag_end..];
+    }
+    if !rest.is_empty() {
+        segments.push((Segment::Text(String::from(rest)), pos));
+    }
+    Ok(segments)
+}
+
+/// Lexes a piece of code found at `start` in the page, keeping token positions relative to the page.
+fn lex_code(code: &str, start: Position, tokens: &mut VecDeque<(Token, Position)>) {
+    let mut lexer = Lexer::new(String::from(code));
+    lexer.init();
+    loop {
+        let (tok, pos) = lexer.next_spanned();
+        if tok == Token::Eof {
+            break;
+        }
+        let pos = if pos.line == 1 {
+            Position{ line: start.line, col: start.col + pos.col - 1 }
+        } else {
+            Position{ line: start.line + pos.line - 1, col: pos.col }
+        };
+        tokens.push_back((tok, pos));
+    }
+}
+
+/// Turns an `.ml` page into a program. Text is printed as is, `<% code %>` is evaluated,
+/// `<%= expr %>` is evaluated and printed and `<%# comment %>` is ignored.
+/// The text becomes `Statement::Text`, written to the output without going through `print`, so it
+/// isn't escaped along with the output of the page nor changed by what the page binds.
+pub fn compile(contents: &str) -> Result<Program, ParseError> {
+    Parser::new(&mut ScriptLexer(VecDeque::from(tokenize(contents)?))).parse_program()
+}
+
+/// The tokens of the program a page compiles to. Those standing for the text and the tags
+/// around output are placed at the start of them.
+pub fn tokenize(contents: &str) -> Result<Vec<(Token, Position)>, ParseError> {
+    let mut tokens = VecDeque::new();
+    for (segment, pos) in scan(contents)? {
+        match segment {
+            Segment::Text(ref text) if text.is_empty() => {},
+            Segment::Text(text) => tokens.push_back((Token::Text(text), pos)),
+            Segment::Output(code) => {
+                tokens.push_back((Token::Ident(String::from("print")), pos));
+                tokens.push_back((Token::Lparen, pos));
+                lex_code(&code, pos, &mut tokens);
+                while tokens.back().is_some_and(|(tok, _)| *tok == Token::Semicolon) {
+                    tokens.pop_back();
+                }
+                let end = tokens.back().map_or(pos, |(_, pos)| *pos);
+                tokens.push_back((Token::Rparen, end));
+                tokens.push_back((Token::Semicolon, end));
+            },
+            Segment::Code(code) => lex_code(&code, pos, &mut tokens),
+            Segment::Comment => {},
+        }
+    }
+    Ok(Vec::from(tokens))
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(io::Error),
+    Syntax(ParseError),
+}
+
+/// Identifies a version of a file; a template is recompiled once this changes.
+#[derive(Clone, Copy, PartialEq)]
+struct Version {
+    modified: SystemTime,
+    len: u64,
+}
+
+/// Compiled templates shared between the workers, so pages aren't parsed again on every request.
+pub struct TemplateCache {
+    templates: Mutex<HashMap<PathBuf, (Version, Arc<Program>)>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// How well the template cache has been doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheStats {
+    pub hits: usize,
+    /// Lookups that had to compile the template, including those for changed files
+    pub misses: usize,
+    pub templates: usize,
+}
+
+impl TemplateCache {
+    pub fn new() -> TemplateCache {
+        TemplateCache{ templates: Mutex::new(HashMap::new()), hits: AtomicUsize::new(0), misses: AtomicUsize::new(0) }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::SeqCst),
+            misses: self.misses.load(Ordering::SeqCst),
+            templates: self.templates.lock().unwrap().len(),
+        }
+    }
+
+    /// Returns the compiled template at `path`, compiling it if it isn't cached or the file changed since.
+    pub fn get(&self, path: &Path) -> Result<Arc<Program>, TemplateError> {
+        let metadata = fs::metadata(path).map_err(TemplateError::Io)?;
+        let version = Version{ modified: metadata.modified().map_err(TemplateError::Io)?, len: metadata.len() };
+        if let Some((cached, program)) = self.templates.lock().unwrap().get(path) {
+            if *cached == version {
+                self.hits.fetch_add(1, Ordering::SeqCst);
+                return Ok(Arc::clone(program));
+            }
+        }
+        self.misses.fetch_add(1, Ordering::SeqCst);
+        // Compile without holding the lock so other pages can still be served meanwhile
+        let contents = fs::read_to_string(path).map_err(TemplateError::Io)?;
+        let program = Arc::new(compile(&contents).map_err(TemplateError::Syntax)?);
+        self.templates.lock().unwrap().insert(path.to_path_buf(), (version, Arc::clone(&program)));
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ TemplateCache, CacheStats, compile, tokenize };
+    use ast::{ Program, Statement };
+    use lexer::Token;
+    use eval::{ State, Eval, RuntimeError, ErrorKind, TemplateLoader };
+    use lexer::Position;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    fn render(page: &str) -> String {
+        let mut output = Vec::new();
+        compile(page).unwrap().eval(&mut State::new(), &mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    fn render_escaped(page: &str) -> String {
+        let mut state = State::new();
+        state.escape_output();
+        let mut output = Vec::new();
+        compile(page).unwrap().eval(&mut state, &mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_tags() {
+        assert_eq!(render("<p>1 + 2 = <%= 1 + 2 %></p>"), "<p>1 + 2 = 3</p>");
+        assert_eq!(render("<% let x = \"%>\"; %><%= x; %>"), "%>");
+        assert_eq!(render("a<%# not <% code %>b"), "ab");
+        assert_eq!(render("<ul>\n  <%- map([1, 2], fn(i) { -%>\n  <li><%= i %></li>\n  <%- }); -%>\n</ul>\n"),
+            "<ul>\n  <li>1</li>\n  <li>2</li>\n</ul>\n");
+    }
+
+    #[test]
+    fn test_text_tokens() {
+        let tokens = tokenize("<p>\n<%= x %></p>").unwrap();
+        assert_eq!(tokens[0], (Token::Text(String::from("<p>\n")), Position{ line: 1, col: 1 }));
+        assert_eq!(tokens.last().unwrap(), &(Token::Text(String::from("</p>")), Position{ line: 2, col: 9 }));
+        // Only the output tag prints, the text depending on no name the page could bind
+        assert!(!tokens.iter().any(|(tok, _)| *tok == Token::Ident(String::from("raw"))));
+        assert_eq!(tokens.iter().filter(|(tok, _)| matches!(tok, Token::Ident(name) if name == "print")).count(), 1);
+        assert_eq!(compile("a").unwrap().statements()[0].as_ref(), &Statement::Text(String::from("a")));
+    }
+
+    #[test]
+    fn test_verbatim_text() {
+        let csv = "name,\"quote\"\\n\r\n<% map([1, 2], fn(i) { %><%= i %>,x\r\n<% }) %>\r\n";
+        assert_eq!(render(csv), "name,\"quote\"\\n\r\n1,x\r\n2,x\r\n\r\n");
+        assert_eq!(render("<% let x = 1; %>\n\t<%= x %>"), "\n\t1");
+        assert_eq!(render("no trailing newline"), "no trailing newline");
+        // Text doesn't go through what the page binds
+        let shadowing = "<% let raw = fn(x) { \"\" }; let print = fn(x) { null }; %><p>\"a\" & <b></p>\n";
+        assert_eq!(render(shadowing), "<p>\"a\" & <b></p>\n");
+        assert_eq!(render_escaped(shadowing), "<p>\"a\" & <b></p>\n");
+    }
+
+    #[test]
+    fn test_whitespace_control() {
+        assert_eq!(render("<h1>\n  <%-\n    let x = 1;\n  -%>\n  <%-# comment -%>  \r\n  <%= x %>\n</h1>\n"), "<h1>\n  1\n</h1>\n");
+        assert_eq!(render("a,  <%-= 1 -%>  \n\n,b"), "a,1\n,b");
+        assert_eq!(render("<%= 3 - 1 %> <%= -1 %>"), "2 -1");
+    }
+
+    #[test]
+    fn test_escaping() {
+        let page = "<p title=\"<%= raw(escape_attr(x)) %>\"><%= x %> <% println(x) %><%= raw(x) %></p>";
+        let page = format!("<% let x = \"<b>&</b>\"; %>{}", page);
+        assert_eq!(render_escaped(&page),
+            "<p title=\"&#x3C;b&#x3E;&#x26;&#x3C;&#x2F;b&#x3E;\">&lt;b&gt;&amp;&lt;/b&gt; &lt;b&gt;&amp;&lt;/b&gt;\n<b>&</b></p>");
+        assert_eq!(render_escaped("<a href=\"/?q=<%= escape_url(\"a&b\") %>\">"), "<a href=\"/?q=a%26b\">");
+    }
+
+    struct MapLoader(HashMap<&'static str, &'static str>);
+
+    impl TemplateLoader for MapLoader {
+        fn load(&self, path: &str) -> Result<Arc<Program>, RuntimeError> {
+            let page = self.0.get(path).ok_or(RuntimeError::new(ErrorKind::Template, "not found"))?;
+            Ok(Arc::new(compile(page).unwrap()))
+        }
+    }
+
+    fn render_page(templates: Vec<(&'static str, &'static str)>) -> String {
+        let loader = MapLoader(templates.into_iter().collect());
+        let page = compile(loader.0["page.ml"]).unwrap();
+        let mut state = State::new();
+        state.set_loader(Rc::new(loader));
+        let mut output = Vec::new();
+        state.render(&page, &mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_layouts() {
+        let base = "<h1><% block(\"title\", \"Untitled\") %></h1><% block(\"content\") %><% block(\"footer\") %>";
+        let section = "<% layout(\"base.ml\"); block(\"title\", \"Section\") %><main><% block(\"content\") %></main>";
+        assert_eq!(render_page(vec![("base.ml", base), ("page.ml", "<% layout(\"base.ml\") %>body")]), "<h1>Untitled</h1>body");
+        assert_eq!(render_page(vec![
+            ("base.ml", base),
+            ("page.ml", "<% layout(\"base.ml\"); let name = \"Page\"; %>\
+                <% block(\"title\", fn() { %><%= name %><% }) %><% block(\"footer\", \"end\") %>body"),
+        ]), "<h1>Page</h1>bodyend");
+        assert_eq!(render_page(vec![
+            ("base.ml", base), ("section.ml", section),
+            ("page.ml", "<% layout(\"section.ml\") %>body"),
+        ]), "<h1>Section</h1><main>body</main>");
+    }
+
+    #[test]
+    fn test_include() {
+        assert_eq!(render_page(vec![
+            ("item.ml", "<li><%= x %><%= item %></li>"),
+            ("page.ml", "<% let x = \"-\"; include(\"item.ml\", {\"item\": 1}); include(\"item.ml\", {\"x\": \"+\", \"item\": 2}) %>"),
+        ]), "<li>-1</li><li>+2</li>");
+        assert_eq!(render_page(vec![("page.ml", "<% include(\"missing.ml\") %>")]), "");
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = compile("<p>\n  <%= 1 + %>\n</p>").unwrap_err();
+        assert_eq!(err.pos.line, 2);
+        let err = compile("<p>\n  <% let = 1; %>\n</p>").unwrap_err();
+        assert_eq!(err.pos, Position{ line: 2, col: 10 });
+        let err = compile("<p>\n\n <%= 1 </p>").unwrap_err();
+        assert_eq!(err.pos, Position{ line: 3, col: 2 });
+        assert_eq!(err.message, "Unterminated <% tag");
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let path = env::temp_dir().join(format!("monkeylang-template-{}.ml", std::process::id()));
+        fs::write(&path, "<%\nlet x = 1;\n%>\n").unwrap();
+        let cache = TemplateCache::new();
+        let first = cache.get(&path).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get(&path).unwrap()));
+        assert_eq!(cache.stats(), CacheStats{ hits: 1, misses: 1, templates: 1 });
+
+        fs::write(&path, "<%\nlet x = 10;\n%>\n").unwrap();
+        let second = cache.get(&path).unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&second, &cache.get(&path).unwrap()));
+        fs::remove_file(&path).unwrap();
+    }
+}
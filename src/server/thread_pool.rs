@@ -1,11 +1,19 @@
+use eval;
 use std::thread;
+use std::cell::Cell;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
 
 pub struct ThreadPool {
     threads: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    sender: mpsc::SyncSender<Message>,
+    receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+    monitor: Monitor,
 }
 
 trait FnBox {
@@ -18,38 +26,149 @@ impl<F: FnOnce()> FnBox for F {
     }
 }
 
-type Job = Box<FnBox + Send>;
+type Job = Box<dyn FnBox + Send>;
+
+thread_local! {
+    static WORKER_ID: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Id of the worker running the current thread, if it belongs to a pool.
+pub fn worker_id() -> Option<usize> {
+    WORKER_ID.with(|id| id.get())
+}
 
 enum Message {
     Job(Job),
     Terminate,
 }
 
+/// Counters updated by the workers of a pool.
+#[derive(Default)]
+struct Counters {
+    /// Jobs sent to the queue and not yet picked up by a worker
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    completed: AtomicUsize,
+    panicked: AtomicUsize,
+}
+
+/// Snapshot of the state of a pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolMetrics {
+    pub workers: usize,
+    /// Workers running a job
+    pub busy: usize,
+    /// Jobs waiting for a worker
+    pub queued: usize,
+    /// How many jobs may wait before `execute` blocks
+    pub capacity: usize,
+    pub completed: usize,
+    /// Jobs that panicked, counted apart from the completed ones
+    pub panicked: usize,
+}
+
+/// Handle to the metrics of a pool that can be kept apart from it.
+#[derive(Clone)]
+pub struct Monitor {
+    counters: Arc<Counters>,
+    workers: usize,
+    capacity: usize,
+}
+
+impl Monitor {
+    pub fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            workers: self.workers,
+            busy: self.counters.busy.load(Ordering::SeqCst),
+            queued: self.counters.queued.load(Ordering::SeqCst),
+            capacity: self.capacity,
+            completed: self.counters.completed.load(Ordering::SeqCst),
+            panicked: self.counters.panicked.load(Ordering::SeqCst),
+        }
+    }
+}
+
 impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        let (sender, receiver) = mpsc::channel();
+    /// Starts `size` workers sharing a queue that holds up to `queue_size` jobs.
+    pub fn new(size: usize, queue_size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
         let receiver = Arc::new(Mutex::new(receiver));
-        let threads = (0..size).map(|id| Worker::new(id, Arc::clone(&receiver))).collect::<Vec<Worker>>();
-        ThreadPool{ threads, sender }
+        let counters = Arc::new(Counters::default());
+        let threads = (0..size).map(|id| Worker::new(id, Arc::clone(&receiver), Arc::clone(&counters))).collect::<Vec<Worker>>();
+        ThreadPool{ threads, sender, receiver, monitor: Monitor{ counters, workers: size, capacity: queue_size } }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Queues a job for the next free worker, blocking while the queue is full.
+    pub fn execute<F>(&mut self, f: F)
         where F: FnOnce() + Send + 'static
     {
+        self.respawn();
+        self.monitor.counters.queued.fetch_add(1, Ordering::SeqCst);
         self.sender.send(Message::Job(Box::new(f))).unwrap();
     }
+
+    /// Whether `execute` would block. Only reliable while jobs are queued from a single thread.
+    pub fn is_full(&self) -> bool {
+        self.monitor.counters.queued.load(Ordering::SeqCst) >= self.monitor.capacity
+    }
+
+    pub fn monitor(&self) -> Monitor {
+        self.monitor.clone()
+    }
+
+    /// Replaces workers whose thread died. Panicking jobs are caught, so this only happens
+    /// if a worker itself fails.
+    fn respawn(&mut self) {
+        for (id, worker) in self.threads.iter_mut().enumerate() {
+            if worker.thread.as_ref().is_some_and(|thread| thread.is_finished()) {
+                let _ = worker.thread.take().unwrap().join();
+                *worker = Worker::new(id, Arc::clone(&self.receiver), Arc::clone(&self.monitor.counters));
+            }
+        }
+    }
+
+    /// Lets the workers finish the jobs queued so far and joins them, giving up after `timeout`.
+    /// Returns false if some workers were still busy when the timeout expired.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        self.terminate(Some(Instant::now() + timeout))
+    }
+
+    fn terminate(&mut self, deadline: Option<Instant>) -> bool {
+        let running = self.threads.iter().filter(|worker| worker.thread.as_ref().is_some_and(|t| !t.is_finished())).count();
+        for _ in 0..running {
+            // The queue may be full of jobs, so only wait for room until the deadline
+            let mut msg = Message::Terminate;
+            while let Err(mpsc::TrySendError::Full(returned)) = self.sender.try_send(msg) {
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    break;
+                }
+                msg = returned;
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        let mut finished = true;
+        for worker in &mut self.threads {
+            if let Some(thread) = worker.thread.take() {
+                if let Some(deadline) = deadline {
+                    while !thread.is_finished() && Instant::now() < deadline {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    if !thread.is_finished() {
+                        // Leave it detached, it will be killed along with the process
+                        finished = false;
+                        continue;
+                    }
+                }
+                let _ = thread.join();
+            }
+        }
+        finished
+    }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        (0..self.threads.len()).for_each(|_| {
-            self.sender.send(Message::Terminate).unwrap();
-        });
-        for thread in &mut self.threads {
-            if let Some(thread) = thread.thread.take() {
-                thread.join().unwrap();
-            }
-        }
+        self.terminate(None);
     }
 }
 
@@ -58,22 +177,96 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, job_rx: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    fn new(id: usize, job_rx: Arc<Mutex<mpsc::Receiver<Message>>>, counters: Arc<Counters>) -> Worker {
         Worker {
-            thread: Some(thread::Builder::new().stack_size(100*1024*1024).spawn(move|| {
-                println!("Thread {} started", id);
+            thread: Some(thread::Builder::new().name(format!("worker-{}", id)).stack_size(eval::STACK_SIZE).spawn(move|| {
+                WORKER_ID.with(|worker_id| worker_id.set(Some(id)));
+                eval::set_stack_size(eval::STACK_SIZE);
                 loop {
-                    let msg = job_rx.lock().unwrap().recv().unwrap();
+                    let msg = job_rx.lock().unwrap().recv();
                     match msg {
-                        Message::Job(job) => {
-                            println!("Thread {} received job", id);
-                            job.call();
+                        Ok(Message::Job(job)) => {
+                            counters.queued.fetch_sub(1, Ordering::SeqCst);
+                            counters.busy.fetch_add(1, Ordering::SeqCst);
+                            // A panic is reported by the panic hook; the worker carries on with the next job
+                            let result = panic::catch_unwind(AssertUnwindSafe(|| job.call()));
+                            counters.busy.fetch_sub(1, Ordering::SeqCst);
+                            match result {
+                                Ok(()) => counters.completed.fetch_add(1, Ordering::SeqCst),
+                                Err(_) => counters.panicked.fetch_add(1, Ordering::SeqCst),
+                            };
                         },
-                        Message::Terminate => break,
+                        Ok(Message::Terminate) | Err(_) => break,
                     }
                 }
-                println!("Thread {} exiting", id);
             }).unwrap())
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::ThreadPool;
+    use std::sync::Arc;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_shutdown_drains_jobs() {
+        let mut pool = ThreadPool::new(2, 16);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..6 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert!(pool.shutdown(Duration::from_secs(10)));
+        assert_eq!(done.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        let mut pool = ThreadPool::new(1, 1);
+        pool.execute(|| thread::sleep(Duration::from_millis(500)));
+        assert!(!pool.shutdown(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_panic_keeps_worker() {
+        let mut pool = ThreadPool::new(1, 4);
+        let monitor = pool.monitor();
+        pool.execute(|| panic!("bad request"));
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(pool.shutdown(Duration::from_secs(10)));
+        let metrics = monitor.metrics();
+        assert_eq!((metrics.workers, metrics.completed, metrics.panicked), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let mut pool = ThreadPool::new(1, 2);
+        let monitor = pool.monitor();
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        started.recv().unwrap();
+        assert!(!pool.is_full());
+        pool.execute(|| {});
+        pool.execute(|| {});
+        assert!(pool.is_full());
+        let metrics = monitor.metrics();
+        assert_eq!((metrics.busy, metrics.queued, metrics.capacity), (1, 2, 2));
+        release.send(()).unwrap();
+        assert!(pool.shutdown(Duration::from_secs(10)));
+        assert_eq!((monitor.metrics().busy, monitor.metrics().queued, monitor.metrics().completed), (0, 0, 3));
+    }
+}
@@ -0,0 +1,39 @@
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use rustls::{ ServerConfig, ServerConnection, StreamOwned };
+use rustls::crypto::ring;
+use rustls_pemfile;
+
+/// Connection that is read and written through TLS.
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Loads a certificate chain and the private key it was issued for from PEM files.
+pub fn load_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?)).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("No certificates found in {}", cert_path.display())));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid(format!("No private key found in {}", key_path.display())))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(format!("{}: {}", cert_path.display(), e)))?;
+    Ok(Arc::new(config))
+}
+
+/// Completes the handshake with a client, after which requests can be read from the returned stream.
+pub fn accept(config: &Arc<ServerConfig>, mut stream: TcpStream) -> io::Result<TlsStream> {
+    let mut conn = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(conn, stream))
+}